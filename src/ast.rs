@@ -0,0 +1,234 @@
+pub mod ast {
+    use std::fmt;
+
+    use crate::{diagnostic::diagnostic::Span, lexer::lexer::TokenType};
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Literal {
+        Int(i64),
+        Float(f64),
+        Str(String),
+    }
+
+    // Operations built into the language
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Builtin {
+        Add,
+        Sub,
+        Mul,
+        Div,
+        // Remainder of integer division, with the sign of the dividend
+        Mod,
+        // Conversions between ints and floats; floats are truncated towards 0
+        IntToFloat,
+        FloatToInt,
+        // Comparisons leave both operands on the stack and push 1 or 0
+        Eq,
+        Ne,
+        Lt,
+        Le,
+        Gt,
+        Ge,
+        // Logical operators treat any number other than 0 as true and push 1 or 0
+        And,
+        Or,
+        Not,
+        // . pops and prints the top of the stack, , prints it without popping
+        Print,
+        Peek,
+        PrintChar,
+        Dbg,
+        Dup,
+        Swap,
+        Nip,
+        Rot,
+        Drop,
+        Over,
+        TwoDup,
+        TwoDrop,
+        Tuck,
+        // n pick pops n and copies the value n below the top, so 0 pick is dup
+        Pick,
+        // Pushes the number of values on the stack
+        Depth,
+    }
+
+    impl Builtin {
+        pub fn from_token(tok_type: &TokenType) -> Option<Builtin> {
+            match tok_type {
+                TokenType::PLUS => Some(Builtin::Add),
+                TokenType::MINUS => Some(Builtin::Sub),
+                TokenType::ASTERISK => Some(Builtin::Mul),
+                TokenType::SLASH => Some(Builtin::Div),
+                TokenType::MOD => Some(Builtin::Mod),
+                TokenType::ITOF => Some(Builtin::IntToFloat),
+                TokenType::FTOI => Some(Builtin::FloatToInt),
+                TokenType::EQUAL => Some(Builtin::Eq),
+                TokenType::NEQUAL => Some(Builtin::Ne),
+                TokenType::LT => Some(Builtin::Lt),
+                TokenType::LTE => Some(Builtin::Le),
+                TokenType::GT => Some(Builtin::Gt),
+                TokenType::GTE => Some(Builtin::Ge),
+                TokenType::AND => Some(Builtin::And),
+                TokenType::OR => Some(Builtin::Or),
+                TokenType::NOT => Some(Builtin::Not),
+                TokenType::PERIOD => Some(Builtin::Print),
+                TokenType::COMMA | TokenType::PEEK => Some(Builtin::Peek),
+                TokenType::CHAR => Some(Builtin::PrintChar),
+                TokenType::DBG => Some(Builtin::Dbg),
+                TokenType::DUP => Some(Builtin::Dup),
+                TokenType::SWAP => Some(Builtin::Swap),
+                TokenType::NIP => Some(Builtin::Nip),
+                TokenType::ROT => Some(Builtin::Rot),
+                TokenType::DROP => Some(Builtin::Drop),
+                TokenType::OVER => Some(Builtin::Over),
+                TokenType::TWODUP => Some(Builtin::TwoDup),
+                TokenType::TWODROP => Some(Builtin::TwoDrop),
+                TokenType::TUCK => Some(Builtin::Tuck),
+                TokenType::PICK => Some(Builtin::Pick),
+                TokenType::DEPTH => Some(Builtin::Depth),
+                _ => None,
+            }
+        }
+
+        // Number of values the builtin needs on the stack and number of values it leaves there
+        pub fn effect(&self) -> (i32, i32) {
+            match self {
+                Builtin::Add | Builtin::Sub | Builtin::Mul | Builtin::Div | Builtin::Mod => (2, 1),
+                Builtin::IntToFloat | Builtin::FloatToInt => (1, 1),
+                Builtin::Eq
+                | Builtin::Ne
+                | Builtin::Lt
+                | Builtin::Le
+                | Builtin::Gt
+                | Builtin::Ge => (2, 3),
+                Builtin::And | Builtin::Or => (2, 1),
+                Builtin::Not => (1, 1),
+                Builtin::Print | Builtin::PrintChar => (1, 0),
+                Builtin::Peek => (1, 1),
+                Builtin::Dbg => (0, 0),
+                Builtin::Dup => (1, 2),
+                Builtin::Swap => (2, 2),
+                Builtin::Nip => (2, 1),
+                Builtin::Rot => (3, 3),
+                Builtin::Drop => (1, 0),
+                Builtin::Over => (2, 3),
+                Builtin::TwoDup => (2, 4),
+                Builtin::TwoDrop => (2, 0),
+                Builtin::Tuck => (2, 3),
+                // The value picked must be on the stack too, which only the parser can check,
+                // when n is a literal
+                Builtin::Pick => (1, 1),
+                Builtin::Depth => (0, 1),
+            }
+        }
+    }
+
+    // The word the builtin is written as
+    impl fmt::Display for Builtin {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let s = match self {
+                Builtin::Add => "+",
+                Builtin::Sub => "-",
+                Builtin::Mul => "*",
+                Builtin::Div => "/",
+                Builtin::Mod => "mod",
+                Builtin::IntToFloat => "itof",
+                Builtin::FloatToInt => "ftoi",
+                Builtin::Eq => "=",
+                Builtin::Ne => "!=",
+                Builtin::Lt => "<",
+                Builtin::Le => "<=",
+                Builtin::Gt => ">",
+                Builtin::Ge => ">=",
+                Builtin::And => "and",
+                Builtin::Or => "or",
+                Builtin::Not => "not",
+                Builtin::Print => ".",
+                Builtin::Peek => ",",
+                Builtin::PrintChar => "char",
+                Builtin::Dbg => "dbg",
+                Builtin::Dup => "dup",
+                Builtin::Swap => "swap",
+                Builtin::Nip => "nip",
+                Builtin::Rot => "rot",
+                Builtin::Drop => "drop",
+                Builtin::Over => "over",
+                Builtin::TwoDup => "2dup",
+                Builtin::TwoDrop => "2drop",
+                Builtin::Tuck => "tuck",
+                Builtin::Pick => "pick",
+                Builtin::Depth => "depth",
+            };
+            write!(f, "{}", s)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum NodeKind {
+        Literal(Literal),
+        Builtin(Builtin),
+        // Pushes the value of a variable
+        Var(String),
+        // Pops the top of the stack into a variable
+        Assign(String),
+        Call(String),
+        If { then: Block, els: Option<Block> },
+        // begin <cond> while <body> repeat; cond leaves the flag that decides whether body runs
+        While { cond: Block, body: Block },
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Node {
+        pub kind: NodeKind,
+        pub row: usize,
+        pub col: usize,
+        pub span: Span,
+    }
+
+    pub type Block = Vec<Node>;
+
+    // Stack effect of a word, written ( a b -- c ) after its name
+    #[derive(Debug, Clone, PartialEq, Eq, Default)]
+    pub struct Signature {
+        pub inputs: Vec<String>,
+        pub outputs: Vec<String>,
+    }
+
+    impl Signature {
+        // Number of values the word takes and number it leaves. Signatures with alternatives,
+        // ( a -- a | a a ), or a run of values, ( a -- a .. 1 ), don't have a fixed effect
+        pub fn effect(&self) -> Option<(i32, i32)> {
+            let mut names = self.inputs.iter().chain(&self.outputs);
+            if names.any(|n| n == "|" || n == "..") {
+                return None;
+            }
+            Some((self.inputs.len() as i32, self.outputs.len() as i32))
+        }
+    }
+
+    impl fmt::Display for Signature {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut parts = self.inputs.clone();
+            parts.push("--".to_string());
+            parts.extend(self.outputs.iter().cloned());
+            write!(f, "( {} )", parts.join(" "))
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct WordDef {
+        pub name: String,
+        pub signature: Option<Signature>,
+        pub body: Block,
+        pub row: usize,
+        pub col: usize,
+    }
+
+    // Words in the order they were defined, and the code outside of any word
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Program {
+        pub words: Vec<WordDef>,
+        pub main: Block,
+    }
+}
@@ -1,81 +1,285 @@
 pub mod compiler {
+    use std::{collections::HashMap, fs};
+
     use crate::{
-        lexer::lexer::{Token, TokenType},
-        op::op::Op,
+        ast::ast::{Block, Builtin, Literal, Node, NodeKind, Program, WordDef},
+        diagnostic::diagnostic::{self, Diagnostic, Span},
+        op::op::{encode, write_sleb, write_uleb, Op, RVM_FLAG_DEBUG, RVM_MAGIC, RVM_VERSION},
+        vm::vm::DebugInfo,
     };
 
     pub struct Compiler {
-        _source_file: String,
-        tokens: Vec<Token>,
+        // HashMap in the form "word name": index of the word's first op
+        words: HashMap<String, usize>,
+        // Variable slots of the word being compiled; every call gets its own set of slots
+        // : word 1 x := ; => x is only visible inside word
+        vars: HashMap<String, usize>,
         pub bytes: Vec<Op>,
         pub const_pool: Vec<i64>,
+        // Source of the node each op was generated from; empty for ops no node made
+        pub spans: Vec<Span>,
+        // (index of a LOAD op, name of the variable it loads)
+        pub var_names: Vec<(usize, String)>,
     }
 
     impl Compiler {
-        pub fn compile(&mut self) -> Result<i32, String> {
-            for i in &self.tokens {
-                match &i.tok_type {
-                    TokenType::EOF => self.bytes.push(Op::HALT),
-                    TokenType::INT(n) => {
-                        self.const_pool.push(*n as i64);
-                        self.bytes.push(Op::PUSHNUM(self.const_pool.len() - 1));
+        fn format_err(&self, node: &Node, message: String) -> Diagnostic {
+            diagnostic::error(&node.span, message)
+        }
+
+        fn emit(&mut self, op: Op, span: &Span) {
+            self.bytes.push(op);
+            self.spans.push(span.clone());
+        }
+
+        fn builtin_op(b: Builtin) -> Op {
+            match b {
+                Builtin::Add => Op::ADD,
+                Builtin::Sub => Op::SUB,
+                Builtin::Mul => Op::MUL,
+                Builtin::Div => Op::DIV,
+                Builtin::Mod => Op::MOD,
+                Builtin::IntToFloat => Op::ITOF,
+                Builtin::FloatToInt => Op::FTOI,
+                Builtin::Eq => Op::EQ,
+                Builtin::Ne => Op::NE,
+                Builtin::Lt => Op::LT,
+                Builtin::Le => Op::LE,
+                Builtin::Gt => Op::GT,
+                Builtin::Ge => Op::GE,
+                Builtin::And => Op::AND,
+                Builtin::Or => Op::OR,
+                Builtin::Not => Op::NOT,
+                Builtin::Print => Op::PRINT,
+                Builtin::Peek => Op::PEEK,
+                Builtin::PrintChar => Op::PRINTC,
+                Builtin::Dbg => Op::DBG,
+                Builtin::Dup => Op::DUP,
+                Builtin::Swap => Op::SWAP,
+                Builtin::Nip => Op::NIP,
+                Builtin::Rot => Op::ROT,
+                Builtin::Drop => Op::DROP,
+                Builtin::Over => Op::OVER,
+                Builtin::TwoDup => Op::TWODUP,
+                Builtin::TwoDrop => Op::TWODROP,
+                Builtin::Tuck => Op::TUCK,
+                Builtin::Pick => Op::PICK,
+                Builtin::Depth => Op::DEPTH,
+            }
+        }
+
+        fn patch_jump(&mut self, idx: usize) {
+            let target = self.bytes.len();
+            self.bytes[idx] = match self.bytes[idx] {
+                Op::JMP(_) => Op::JMP(target),
+                Op::JZ(_) => Op::JZ(target),
+                Op::JNZ(_) => Op::JNZ(target),
+                op => op,
+            };
+        }
+
+        fn push_num(&mut self, n: i64, node: &Node) {
+            self.const_pool.push(n);
+            self.emit(Op::PUSHNUM(self.const_pool.len() - 1), &node.span);
+        }
+
+        // The constant pool holds the bits of the float
+        fn push_float(&mut self, f: f64, node: &Node) {
+            self.const_pool.push(f.to_bits() as i64);
+            self.emit(Op::PUSHFLT(self.const_pool.len() - 1), &node.span);
+        }
+
+        fn push_str(&mut self, s: &str, node: &Node) {
+            self.emit(Op::PUSHSTR(self.const_pool.len()), &node.span);
+            for c in s.chars() {
+                self.const_pool.push(c as i64);
+            }
+            self.const_pool.push(0);
+        }
+
+        fn var_slot(&mut self, var: &str) -> usize {
+            let slot = self.vars.len();
+            *self.vars.entry(var.to_string()).or_insert(slot)
+        }
+
+        fn compile_block(&mut self, block: &Block) -> Result<(), Diagnostic> {
+            for node in block {
+                self.compile_node(node)?;
+            }
+            Ok(())
+        }
+
+        fn compile_node(&mut self, node: &Node) -> Result<(), Diagnostic> {
+            let span = &node.span;
+            match &node.kind {
+                NodeKind::Literal(Literal::Int(n)) => self.push_num(*n, node),
+                NodeKind::Literal(Literal::Float(f)) => self.push_float(*f, node),
+                NodeKind::Literal(Literal::Str(s)) => self.push_str(s, node),
+                NodeKind::Builtin(b) => self.emit(Self::builtin_op(*b), span),
+                NodeKind::Var(v) => match self.vars.get(v) {
+                    Some(slot) => {
+                        self.var_names.push((self.bytes.len(), v.clone()));
+                        self.emit(Op::LOAD(*slot), span);
                     }
-                    TokenType::STR(s) => {
-                        self.bytes.push(Op::PUSHSTR(self.const_pool.len()));
-                        for c in s.chars() {
-                            self.const_pool.push(c as i64);
+                    None => return Err(self.format_err(node, format!("Invalid: {} undefined", v))),
+                },
+                NodeKind::Assign(v) => {
+                    let slot = self.var_slot(v);
+                    self.emit(Op::STORE(slot), span);
+                }
+                NodeKind::Call(w) => match self.words.get(w) {
+                    Some(addr) => self.emit(Op::CALL(*addr), span),
+                    None => return Err(self.format_err(node, format!("Invalid: {} undefined", w))),
+                },
+                NodeKind::If { then, els } => {
+                    let jz = self.bytes.len();
+                    self.emit(Op::JZ(0), span);
+                    self.compile_block(then)?;
+                    match els {
+                        Some(els) => {
+                            let jmp = self.bytes.len();
+                            self.emit(Op::JMP(0), span);
+                            self.patch_jump(jz);
+                            self.compile_block(els)?;
+                            self.patch_jump(jmp);
                         }
-                        self.const_pool.push(0);
+                        None => self.patch_jump(jz),
                     }
-                    TokenType::IDENT(_) => todo!("{:?}", i),
-                    TokenType::PLUS => self.bytes.push(Op::ADD),
-                    TokenType::MINUS => self.bytes.push(Op::SUB),
-                    TokenType::ASTERISK => self.bytes.push(Op::MUL),
-                    TokenType::SLASH => self.bytes.push(Op::DIV),
-                    TokenType::COMMA => todo!("{:?}", i),
-                    TokenType::SET => todo!("{:?}", i),
-                    TokenType::EQUAL => todo!("{:?}", i),
-                    TokenType::NEQUAL => todo!("{:?}", i),
-                    TokenType::LTE => todo!("{:?}", i),
-                    TokenType::LT => todo!("{:?}", i),
-                    TokenType::GTE => todo!("{:?}", i),
-                    TokenType::GT => todo!("{:?}", i),
-                    TokenType::DUP => self.bytes.push(Op::DUP),
-                    TokenType::SWAP => self.bytes.push(Op::SWAP),
-                    TokenType::NIP => self.bytes.push(Op::NIP),
-                    TokenType::ROT => self.bytes.push(Op::ROT),
-                    TokenType::PEEK => todo!("{:?}", i),
-                    TokenType::DBG => self.bytes.push(Op::DBG),
-                    TokenType::PERIOD => self.bytes.push(Op::PRINTI),
-                    // TokenType::PRINT => self.bytes.push(Op::PRINTS),
-                    TokenType::CHAR => todo!("{:?}", i),
-                    TokenType::QMARK => todo!("{:?}", i),
-                    TokenType::COLON => todo!("{:?}", i),
-                    TokenType::SEMICOLON => todo!("{:?}", i),
-                    TokenType::LPAREN => todo!("{:?}", i),
-                    TokenType::RPAREN => todo!("{:?}", i),
-                    TokenType::EM => todo!("{:?}", i),
-                    TokenType::IF(_) => todo!("{:?}", i),
-                    TokenType::ELSE(_) => todo!("{:?}", i),
-                    TokenType::WHILE(_, _) => todo!("{:?}", i),
-                    TokenType::END(_, _) => todo!("{:?}", i),
+                }
+                NodeKind::While { cond, body } => {
+                    let start = self.bytes.len();
+                    self.compile_block(cond)?;
+                    let exit = self.bytes.len();
+                    self.emit(Op::JZ(0), span);
+                    self.compile_block(body)?;
+                    self.emit(Op::JMP(start), span);
+                    self.patch_jump(exit);
                 }
             }
-            println!("COMPILER:");
-            for i in &self.bytes {
-                print!("{} ", i);
+            Ok(())
+        }
+
+        // Compiles the body of `: name ... ;` once; it only runs when called. The name is
+        // registered before the body so the word can call itself
+        fn compile_word(&mut self, word: &WordDef) -> Result<(), Diagnostic> {
+            self.words.insert(word.name.clone(), self.bytes.len());
+            let vars = std::mem::take(&mut self.vars);
+            self.compile_block(&word.body)?;
+            self.emit(Op::RET, &Span::default());
+            self.vars = vars;
+            Ok(())
+        }
+
+        // Words are placed first, behind a jump to the start of the program
+        pub fn compile(&mut self, program: &Program) -> Result<i32, Diagnostic> {
+            if !program.words.is_empty() {
+                self.emit(Op::JMP(0), &Span::default());
+                for word in &program.words {
+                    self.compile_word(word)?;
+                }
+                self.patch_jump(0);
             }
-            println!("\n");
+            self.compile_block(&program.main)?;
+            self.emit(Op::HALT, &Span::default());
             Ok(0)
         }
+
+        // Encodes the ops into bytecode for vm::VM, along with tables mapping the byte offset
+        // of each op to the source it was generated from and of each LOAD to its variable
+        pub fn assemble(&self) -> (Vec<u8>, DebugInfo) {
+            let (code, offsets) = encode(&self.bytes);
+            let spans = offsets
+                .iter()
+                .zip(&self.spans)
+                .filter(|(_, span)| !span.file.is_empty())
+                .map(|(offset, span)| (*offset, span.clone()))
+                .collect();
+            let vars = self
+                .var_names
+                .iter()
+                .map(|(i, name)| (offsets[*i], name.clone()))
+                .collect();
+            (code, DebugInfo { spans, vars })
+        }
+
+        // Serializes the compiled program into the .rvm format:
+        //   magic "RVM\0", version u16, flags u16
+        //   constant section: u32 count, then each constant as a signed LEB128
+        //   code section: u32 length, then the bytecode produced by op::encode
+        //   debug section (only if RVM_FLAG_DEBUG is set): u32 file count, then the u32 length
+        //     and the path of each file; u32 entry count, then the byte offset of an op, the
+        //     index of its file and the byte offsets its source starts and ends at, each as
+        //     unsigned LEB128; u32 variable count, then the byte offset of a LOAD as unsigned
+        //     LEB128, the u32 length and the name of the variable it loads
+        // Fixed width integers are little endian
+        pub fn to_rvm(&self, debug: bool) -> Vec<u8> {
+            let (code, debug_info) = self.assemble();
+            let mut out = vec![];
+            out.extend_from_slice(RVM_MAGIC);
+            out.extend_from_slice(&RVM_VERSION.to_le_bytes());
+            let flags = if debug { RVM_FLAG_DEBUG } else { 0 };
+            out.extend_from_slice(&flags.to_le_bytes());
+
+            out.extend_from_slice(&(self.const_pool.len() as u32).to_le_bytes());
+            for c in &self.const_pool {
+                write_sleb(&mut out, *c);
+            }
+
+            out.extend_from_slice(&(code.len() as u32).to_le_bytes());
+            out.extend_from_slice(&code);
+
+            if debug {
+                // Imported files make ops come from more than one file
+                let mut files: Vec<&str> = vec![];
+                let mut entries = vec![];
+                for (offset, span) in &debug_info.spans {
+                    let file = match files.iter().position(|f| **f == *span.file) {
+                        Some(i) => i,
+                        None => {
+                            files.push(&span.file);
+                            files.len() - 1
+                        }
+                    };
+                    entries.push((*offset, file, span.start, span.end));
+                }
+                out.extend_from_slice(&(files.len() as u32).to_le_bytes());
+                for f in files {
+                    out.extend_from_slice(&(f.len() as u32).to_le_bytes());
+                    out.extend_from_slice(f.as_bytes());
+                }
+                out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                for (offset, file, start, end) in entries {
+                    write_uleb(&mut out, offset as u64);
+                    write_uleb(&mut out, file as u64);
+                    write_uleb(&mut out, start as u64);
+                    write_uleb(&mut out, end as u64);
+                }
+                out.extend_from_slice(&(debug_info.vars.len() as u32).to_le_bytes());
+                for (offset, name) in &debug_info.vars {
+                    write_uleb(&mut out, *offset as u64);
+                    out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                    out.extend_from_slice(name.as_bytes());
+                }
+            }
+            out
+        }
+
+        pub fn write_rvm(&self, path: &str, debug: bool) -> Result<(), String> {
+            match fs::write(path, self.to_rvm(debug)) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("Error writing {}: {}", path, e)),
+            }
+        }
     }
 
-    pub fn new(_source_file: String, tokens: Vec<Token>) -> Compiler {
+    pub fn new() -> Compiler {
         Compiler {
-            _source_file,
-            tokens,
+            words: HashMap::new(),
+            vars: HashMap::new(),
             bytes: vec![],
             const_pool: vec![],
+            spans: vec![],
+            var_names: vec![],
         }
     }
 }
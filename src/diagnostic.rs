@@ -0,0 +1,132 @@
+pub mod diagnostic {
+    use std::{fmt, ops::Range, rc::Rc};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Severity {
+        Error,
+        Warning,
+    }
+
+    impl fmt::Display for Severity {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Severity::Error => write!(f, "error"),
+                Severity::Warning => write!(f, "warning"),
+            }
+        }
+    }
+
+    // Where a token or node came from: the path of its file and the byte offsets of its text,
+    // end excluded
+    #[derive(Debug, Clone, PartialEq, Eq, Default)]
+    pub struct Span {
+        pub file: Rc<str>,
+        pub start: usize,
+        pub end: usize,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Diagnostic {
+        pub severity: Severity,
+        pub file: String,
+        // Byte offsets of the offending text; None when the problem isn't in the source, like a
+        // file that can't be read
+        pub span: Option<Range<usize>>,
+        pub message: String,
+        pub notes: Vec<String>,
+    }
+
+    pub fn error(span: &Span, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            file: span.file.to_string(),
+            span: Some(span.start..span.end),
+            message,
+            notes: vec![],
+        }
+    }
+
+    // Row and column, both starting at 1, of a byte offset into source
+    pub fn position(source: &str, offset: usize) -> (usize, usize) {
+        let offset = offset.min(source.len());
+        let before = source.get(..offset).unwrap_or(source);
+        let row = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (row, before[line_start..].chars().count() + 1)
+    }
+
+    // Errors that aren't about any source, like a failed write
+    impl From<String> for Diagnostic {
+        fn from(message: String) -> Diagnostic {
+            Diagnostic {
+                severity: Severity::Error,
+                file: String::new(),
+                span: None,
+                message,
+                notes: vec![],
+            }
+        }
+    }
+
+    impl Diagnostic {
+        pub fn with_note(mut self, note: String) -> Diagnostic {
+            self.notes.push(note);
+            self
+        }
+
+        // Formats the diagnostic with the line of source it points at and a caret under the
+        // offending text:
+        //   test.rorth:2:3: error: `+` expects int int or float float, got str int
+        //    2 | 1 + "a"
+        //      |   ^
+        // source is the contents of file; without it, only the message and notes are printed
+        pub fn render(&self, source: Option<&str>) -> String {
+            let mut out = String::new();
+            match (source, &self.span) {
+                (Some(src), Some(span)) => {
+                    let (row, col) = position(src, span.start);
+                    out.push_str(&format!(
+                        "{}:{}:{}: {}: {}\n",
+                        self.file, row, col, self.severity, self.message
+                    ));
+                    let line = src.lines().nth(row - 1).unwrap_or("");
+                    // The underline stops at the end of the line for text that spans lines
+                    let len = src
+                        .get(span.start..span.end.max(span.start))
+                        .map_or(0, |s| s.lines().next().unwrap_or("").chars().count())
+                        .max(1);
+                    // Tabs are kept so the caret lines up with the text above it
+                    let indent: String = line
+                        .chars()
+                        .take(col - 1)
+                        .map(|c| if c == '\t' { '\t' } else { ' ' })
+                        .collect();
+                    let gutter = row.to_string();
+                    out.push_str(&format!(" {} | {}\n", gutter, line));
+                    out.push_str(&format!(
+                        " {} | {}{}\n",
+                        " ".repeat(gutter.len()),
+                        indent,
+                        "^".repeat(len)
+                    ));
+                }
+                _ => out.push_str(&format!("{}\n", self)),
+            }
+            for note in &self.notes {
+                out.push_str(&format!("note: {}\n", note));
+            }
+            out
+        }
+    }
+
+    // The message without source; notes are left to render
+    impl fmt::Display for Diagnostic {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            if self.file.is_empty() {
+                write!(f, "{}: {}", self.severity, self.message)
+            } else {
+                write!(f, "{}: {}: {}", self.file, self.severity, self.message)
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+pub mod interpreter {
+    use std::{
+        collections::HashMap,
+        fmt,
+        io::{self, Write},
+        rc::Rc,
+    };
+
+    use crate::{
+        ast::ast::{Block, Builtin, Literal, Node, NodeKind, Program, WordDef},
+        diagnostic::diagnostic::{self, Diagnostic},
+        value::value::{self, Machine, Prim},
+    };
+
+    // Maximum depth of nested word calls before the interpreter gives up, the same as the vm's
+    const MAX_FRAMES: usize = 1 << 16;
+
+    // Work left to run, kept on the heap so calls and blocks don't recurse on the native stack
+    enum Task<'a> {
+        // Runs the nodes of a block from the index on
+        Block(&'a Block, usize),
+        // Checks the flag left by the condition of a loop, and runs the body and the condition
+        // again if it's set
+        Loop(&'a Node, &'a Block, &'a Block),
+        // Ends a word call, dropping its variables
+        Return,
+    }
+
+    pub type Value = value::Value<String>;
+
+    impl fmt::Display for Value {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Value::Int(i) => write!(f, "{}", i),
+                // Same as printf's %f
+                Value::Float(x) => write!(f, "{:.6}", x),
+                Value::Str(s) => write!(f, "{}", s),
+            }
+        }
+    }
+
+    pub struct Interpreter {
+        // HashMap in the form "word name": definition
+        words: HashMap<String, Rc<WordDef>>,
+        // Variables of each active word call; the first holds the variables of the program
+        frames: Vec<HashMap<String, Value>>,
+        pub stack: Vec<Value>,
+        out: Box<dyn Write>,
+    }
+
+    impl Machine<String> for Interpreter {
+        fn stack(&mut self) -> &mut Vec<Value> {
+            &mut self.stack
+        }
+    }
+
+    pub fn new() -> Interpreter {
+        Interpreter {
+            words: HashMap::new(),
+            frames: vec![HashMap::new()],
+            stack: vec![],
+            out: Box::new(io::stdout()),
+        }
+    }
+
+    impl Interpreter {
+        // Sends everything the program prints to out instead of stdout
+        pub fn set_output(&mut self, out: Box<dyn Write>) {
+            self.out = out;
+        }
+
+        // Variables of the program, outside of any word
+        pub fn vars(&mut self) -> &mut HashMap<String, Value> {
+            &mut self.frames[0]
+        }
+
+        fn format_err(&self, node: &Node, message: String) -> Diagnostic {
+            diagnostic::error(&node.span, message)
+        }
+
+        fn frame(&mut self) -> &mut HashMap<String, Value> {
+            self.frames.last_mut().unwrap()
+        }
+
+        fn print_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let v = match op {
+                Builtin::Peek => {
+                    let sp = self.top(op, 1)?;
+                    self.stack[sp].clone()
+                }
+                _ => self.pop(op)?,
+            };
+            write!(self.out, "{} ", v).map_err(|e| e.to_string())
+        }
+
+        fn printc_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let c = self.pop_int(op)?;
+            match char::from_u32(c as u32) {
+                Some(c) => write!(self.out, "{}", c).map_err(|e| e.to_string()),
+                None => Err(format!("Invalid {:?}: {} is not a character", op, c)),
+            }
+        }
+
+        fn dbg_op(&mut self) -> Result<(), String> {
+            let mut s = "Debug: ".to_string();
+            for i in &self.stack {
+                s.push_str(&format!("{} ", i));
+            }
+            writeln!(self.out, "{}", s).map_err(|e| e.to_string())
+        }
+
+        fn stack_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let (req, _) = op.effect();
+            let sp = self.top(op, req as usize)?;
+            match op {
+                Builtin::Dup => self.stack.push(self.stack[sp].clone()),
+                Builtin::Swap => self.stack.swap(sp - 1, sp),
+                Builtin::Nip => {
+                    self.stack.remove(sp - 1);
+                }
+                Builtin::Rot => {
+                    let v = self.stack.remove(sp - 2);
+                    self.stack.push(v);
+                }
+                Builtin::Drop => {
+                    self.stack.pop();
+                }
+                Builtin::Over => self.stack.push(self.stack[sp - 1].clone()),
+                Builtin::TwoDup => self.stack.extend_from_within(sp - 1..),
+                Builtin::TwoDrop => self.stack.truncate(sp - 1),
+                Builtin::Tuck => self.stack.insert(sp - 1, self.stack[sp].clone()),
+                _ => unreachable!(),
+            }
+            Ok(())
+        }
+
+        fn pick_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let n = self.pop_int(op)?;
+            if n < 0 || n as usize >= self.stack.len() {
+                return Err(format!(
+                    "Invalid {:?}: Not enough values on the stack to pick {}",
+                    op, n
+                ));
+            }
+            let v = self.stack[self.stack.len() - 1 - n as usize].clone();
+            self.stack.push(v);
+            Ok(())
+        }
+
+        fn builtin_op(&mut self, op: &Builtin) -> Result<(), String> {
+            match op {
+                Builtin::Add => self.prim_op(op, Prim::Add),
+                Builtin::Sub => self.prim_op(op, Prim::Sub),
+                Builtin::Mul => self.prim_op(op, Prim::Mul),
+                Builtin::Div => self.prim_op(op, Prim::Div),
+                Builtin::Mod => self.prim_op(op, Prim::Mod),
+                Builtin::IntToFloat => self.prim_op(op, Prim::IntToFloat),
+                Builtin::FloatToInt => self.prim_op(op, Prim::FloatToInt),
+                Builtin::Eq => self.prim_op(op, Prim::Eq),
+                Builtin::Ne => self.prim_op(op, Prim::Ne),
+                Builtin::Lt => self.prim_op(op, Prim::Lt),
+                Builtin::Le => self.prim_op(op, Prim::Le),
+                Builtin::Gt => self.prim_op(op, Prim::Gt),
+                Builtin::Ge => self.prim_op(op, Prim::Ge),
+                Builtin::And => self.prim_op(op, Prim::And),
+                Builtin::Or => self.prim_op(op, Prim::Or),
+                Builtin::Not => self.prim_op(op, Prim::Not),
+                Builtin::Print | Builtin::Peek => self.print_op(op),
+                Builtin::PrintChar => self.printc_op(op),
+                Builtin::Dbg => self.dbg_op(),
+                Builtin::Dup
+                | Builtin::Swap
+                | Builtin::Nip
+                | Builtin::Rot
+                | Builtin::Drop
+                | Builtin::Over
+                | Builtin::TwoDup
+                | Builtin::TwoDrop
+                | Builtin::Tuck => self.stack_op(op),
+                Builtin::Pick => self.pick_op(op),
+                Builtin::Depth => {
+                    self.stack.push(Value::Int(self.stack.len() as i64));
+                    Ok(())
+                }
+            }
+        }
+
+        // Pops the flag left by an IF or WHILE condition
+        fn pop_flag(&mut self, node: &Node) -> Result<bool, Diagnostic> {
+            match self.stack.pop() {
+                Some(Value::Int(i)) => Ok(i != 0),
+                Some(v) => Err(self.format_err(
+                    node,
+                    format!("Invalid condition: expected an int, got {:?}", v),
+                )),
+                None => Err(self.format_err(
+                    node,
+                    "Invalid condition: Not enough values on the stack".to_string(),
+                )),
+            }
+        }
+
+        fn exec_node(&mut self, node: &Node) -> Result<(), Diagnostic> {
+            match &node.kind {
+                NodeKind::Literal(Literal::Int(i)) => self.stack.push(Value::Int(*i)),
+                NodeKind::Literal(Literal::Float(f)) => self.stack.push(Value::Float(*f)),
+                NodeKind::Literal(Literal::Str(s)) => self.stack.push(Value::Str(s.clone())),
+                NodeKind::Builtin(b) => {
+                    if let Err(e) = self.builtin_op(b) {
+                        return Err(self.format_err(node, e));
+                    }
+                }
+                NodeKind::Var(v) => match self.frame().get(v) {
+                    Some(val) => {
+                        let val = val.clone();
+                        self.stack.push(val)
+                    }
+                    None => return Err(self.format_err(node, format!("Invalid: {} undefined", v))),
+                },
+                NodeKind::Assign(v) => match self.stack.pop() {
+                    Some(val) => {
+                        self.frame().insert(v.clone(), val);
+                    }
+                    None => {
+                        return Err(self.format_err(
+                            node,
+                            format!(
+                                "Invalid assignment to {}: Not enough values on the stack",
+                                v
+                            ),
+                        ))
+                    }
+                },
+                NodeKind::Call(_) | NodeKind::If { .. } | NodeKind::While { .. } => {
+                    unreachable!("control flow is run by exec")
+                }
+            }
+            Ok(())
+        }
+
+        // Runs main with the words defined so far. Calls, ifs and loops push the blocks they
+        // run onto tasks instead of recursing
+        fn exec<'a>(
+            &mut self,
+            words: &'a HashMap<String, Rc<WordDef>>,
+            main: &'a Block,
+        ) -> Result<(), Diagnostic> {
+            let mut tasks = vec![Task::Block(main, 0)];
+            while let Some(task) = tasks.pop() {
+                let (block, i) = match task {
+                    Task::Block(block, i) => (block, i),
+                    Task::Loop(node, cond, body) => {
+                        if self.pop_flag(node)? {
+                            tasks.push(Task::Loop(node, cond, body));
+                            tasks.push(Task::Block(cond, 0));
+                            tasks.push(Task::Block(body, 0));
+                        }
+                        continue;
+                    }
+                    Task::Return => {
+                        self.frames.pop();
+                        continue;
+                    }
+                };
+                let node = match block.get(i) {
+                    Some(n) => n,
+                    None => continue,
+                };
+                tasks.push(Task::Block(block, i + 1));
+                match &node.kind {
+                    NodeKind::Call(name) => {
+                        let word = match words.get(name) {
+                            Some(w) => w,
+                            None => {
+                                return Err(
+                                    self.format_err(node, format!("Invalid: {} undefined", name))
+                                )
+                            }
+                        };
+                        if self.frames.len() >= MAX_FRAMES {
+                            return Err(self.format_err(
+                                node,
+                                format!("Invalid call to {}: Call stack overflow", name),
+                            ));
+                        }
+                        self.frames.push(HashMap::new());
+                        tasks.push(Task::Return);
+                        tasks.push(Task::Block(&word.body, 0));
+                    }
+                    NodeKind::If { then, els } => {
+                        if self.pop_flag(node)? {
+                            tasks.push(Task::Block(then, 0));
+                        } else if let Some(els) = els {
+                            tasks.push(Task::Block(els, 0));
+                        }
+                    }
+                    NodeKind::While { cond, body } => {
+                        tasks.push(Task::Loop(node, cond, body));
+                        tasks.push(Task::Block(cond, 0));
+                    }
+                    _ => self.exec_node(node)?,
+                }
+            }
+            Ok(())
+        }
+
+        // Defines the program's words, then runs the code outside of them
+        pub fn interpret(&mut self, program: Program) -> Result<(), Diagnostic> {
+            // The words are borrowed from a copy of the map while the interpreter changes, and
+            // the program's words are only kept if it runs without errors
+            let mut words = self.words.clone();
+            for word in program.words {
+                words.insert(word.name.clone(), Rc::new(word));
+            }
+            let res = self.exec(&words, &program.main);
+            // A call that failed leaves its variables behind
+            self.frames.truncate(1);
+            res?;
+            self.words = words;
+            self.out
+                .flush()
+                .map_err(|e| Diagnostic::from(e.to_string()))
+        }
+    }
+}
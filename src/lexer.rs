@@ -1,17 +1,17 @@
 pub mod lexer {
-    use crate::parser::parser;
-    use std::{collections::HashMap, fs};
+    use crate::diagnostic::diagnostic::{self, Diagnostic, Span};
+    use std::{
+        collections::{HashMap, HashSet},
+        env, fs,
+        path::{Path, PathBuf},
+        rc::Rc,
+    };
 
-    #[derive(Debug, PartialEq, Eq, Clone)]
-    pub enum EndBlock {
-        Cond,
-        Loop,
-    }
-
-    #[derive(Debug, PartialEq, Eq, Clone)]
+    #[derive(Debug, PartialEq, Clone)]
     pub enum TokenType {
         EOF,
-        INT(u32),
+        INT(i64),
+        FLOAT(f64),
         STR(String),
         IDENT(String),
         PLUS,
@@ -31,6 +31,13 @@ pub mod lexer {
         SWAP,
         NIP,
         ROT,
+        DROP,
+        OVER,
+        TWODUP,
+        TWODROP,
+        TUCK,
+        PICK,
+        DEPTH,
         PEEK,
         DBG,
         CHAR,
@@ -39,17 +46,28 @@ pub mod lexer {
         SEMICOLON,
         LPAREN,
         RPAREN,
-        EM,
-        IF(i32),
-        ELSE(i32),
-        WHILE(Box<TokenType>, i32),
-        END(EndBlock, i32),
+        // The text between the parentheses after the name of a word, ( a b -- c )
+        SIGNATURE(String),
+        IF,
+        ELSE,
+        BEGIN,
+        WHILE,
+        REPEAT,
+        END,
+        AND,
+        OR,
+        NOT,
+        MOD,
+        ITOF,
+        FTOI,
     }
 
     #[derive(Debug, Clone)]
     pub struct Token {
+        // Row and column of the first character of the token
         pub row: usize,
         pub col: usize,
+        pub span: Span,
         pub tok_type: TokenType,
     }
     impl Token {
@@ -57,21 +75,39 @@ pub mod lexer {
             Token {
                 col: 0,
                 row: 0,
+                span: Span::default(),
                 tok_type: TokenType::EOF,
             }
         }
     }
+    impl Default for Token {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
 
     pub struct Lexer {
         pos: usize,
         col: usize,
         row: usize,
         peek: usize,
+        // Byte offset of char in the source
+        byte: usize,
+        // Byte offset, row and column of the token being read
+        start: (usize, usize, usize),
         char: char,
         source: Vec<char>,
-        source_file: String,
+        file: Rc<str>,
         ident: HashMap<String, TokenType>,
+        // Directories searched for imports after the one holding the file, in order
+        include: Vec<PathBuf>,
+        // Files importing this one, outermost first
+        chain: Vec<String>,
+        // Canonical paths of the files imported so far, shared by every file of the program
+        imported: HashSet<PathBuf>,
         pub tokens: Vec<Token>,
+        // Errors found so far; the lexer skips past each one and keeps going
+        pub errors: Vec<Diagnostic>,
     }
 
     pub fn new(source_file: String, code: String) -> Lexer {
@@ -80,28 +116,62 @@ pub mod lexer {
             col: 1,
             row: 1,
             peek: 1,
+            byte: 0,
+            start: (0, 1, 1),
             char: ' ',
             source: code.chars().collect(),
-            source_file,
+            file: source_file.into(),
             ident: HashMap::new(),
+            include: vec![],
+            chain: vec![],
+            imported: HashSet::new(),
             tokens: vec![],
+            errors: vec![],
         };
-        l.char = l.source[l.pos];
-        // l.ident.insert("dup".to_string(), TokenType::DUP);
-        // l.ident.insert("swap".to_string(), TokenType::SWAP);
-        // l.ident.insert("nip".to_string(), TokenType::NIP);
-        // l.ident.insert("rot".to_string(), TokenType::ROT);
+        l.char = l.source.first().copied().unwrap_or('\0');
+        l.ident.insert("dup".to_string(), TokenType::DUP);
+        l.ident.insert("swap".to_string(), TokenType::SWAP);
+        l.ident.insert("nip".to_string(), TokenType::NIP);
+        l.ident.insert("rot".to_string(), TokenType::ROT);
+        l.ident.insert("drop".to_string(), TokenType::DROP);
+        l.ident.insert("over".to_string(), TokenType::OVER);
+        l.ident.insert("2dup".to_string(), TokenType::TWODUP);
+        l.ident.insert("2drop".to_string(), TokenType::TWODROP);
+        l.ident.insert("tuck".to_string(), TokenType::TUCK);
+        l.ident.insert("pick".to_string(), TokenType::PICK);
+        l.ident.insert("depth".to_string(), TokenType::DEPTH);
+        l.ident.insert("+".to_string(), TokenType::PLUS);
+        l.ident.insert("-".to_string(), TokenType::MINUS);
+        l.ident.insert("*".to_string(), TokenType::ASTERISK);
+        l.ident.insert("/".to_string(), TokenType::SLASH);
+        l.ident.insert(".".to_string(), TokenType::PERIOD);
+        l.ident.insert(",".to_string(), TokenType::COMMA);
+        l.ident.insert("=".to_string(), TokenType::EQUAL);
+        l.ident.insert("!=".to_string(), TokenType::NEQUAL);
+        l.ident.insert("<".to_string(), TokenType::LT);
+        l.ident.insert("<=".to_string(), TokenType::LTE);
+        l.ident.insert(">".to_string(), TokenType::GT);
+        l.ident.insert(">=".to_string(), TokenType::GTE);
+        l.ident.insert("?".to_string(), TokenType::QMARK);
+        l.ident.insert(":".to_string(), TokenType::COLON);
+        l.ident.insert(";".to_string(), TokenType::SEMICOLON);
+        l.ident.insert(":=".to_string(), TokenType::SET);
+        l.ident.insert(")".to_string(), TokenType::RPAREN);
         l.ident.insert("dbg".to_string(), TokenType::DBG);
         l.ident.insert("char".to_string(), TokenType::CHAR);
         l.ident.insert("set".to_string(), TokenType::SET);
-        l.ident.insert("if".to_string(), TokenType::IF(0));
-        l.ident.insert("else".to_string(), TokenType::ELSE(0));
-        l.ident.insert(
-            "while".to_string(),
-            TokenType::WHILE(Box::new(TokenType::EQUAL), 0),
-        );
-        l.ident
-            .insert("end".to_string(), TokenType::END(EndBlock::Cond, 0));
+        l.ident.insert("if".to_string(), TokenType::IF);
+        l.ident.insert("else".to_string(), TokenType::ELSE);
+        l.ident.insert("begin".to_string(), TokenType::BEGIN);
+        l.ident.insert("while".to_string(), TokenType::WHILE);
+        l.ident.insert("repeat".to_string(), TokenType::REPEAT);
+        l.ident.insert("end".to_string(), TokenType::END);
+        l.ident.insert("and".to_string(), TokenType::AND);
+        l.ident.insert("or".to_string(), TokenType::OR);
+        l.ident.insert("not".to_string(), TokenType::NOT);
+        l.ident.insert("mod".to_string(), TokenType::MOD);
+        l.ident.insert("itof".to_string(), TokenType::ITOF);
+        l.ident.insert("ftoi".to_string(), TokenType::FTOI);
         l
     }
 
@@ -113,6 +183,9 @@ pub mod lexer {
         }
 
         pub fn advance_token(&mut self) {
+            if self.pos < self.source.len() {
+                self.byte += self.char.len_utf8();
+            }
             self.pos += 1;
             self.col += 1;
             self.peek += 1;
@@ -122,7 +195,6 @@ pub mod lexer {
             }
             if self.pos >= self.source.len() {
                 self.char = '\0';
-                return;
             } else {
                 self.char = self.source[self.pos];
             }
@@ -138,160 +210,364 @@ pub mod lexer {
             }
         }
 
+        // Span from the start of the token being read to the end of the current character
+        fn span(&self, start: usize) -> Span {
+            let end = match self.char {
+                '\0' => self.byte,
+                c => self.byte + c.len_utf8(),
+            };
+            Span {
+                file: self.file.clone(),
+                start,
+                end,
+            }
+        }
+
+        fn error(&self, start: usize, message: String) -> Diagnostic {
+            diagnostic::error(&self.span(start), message)
+        }
+
         pub fn make_token(&self, token_type: TokenType) -> Token {
+            let (start, row, col) = self.start;
             Token {
-                col: self.col,
-                row: self.row,
+                col,
+                row,
+                span: self.span(start),
                 tok_type: token_type,
             }
         }
 
-        fn parse_string(&mut self) {
-            self.advance_token();
-            let mut ident = vec![];
-            ident.push(self.char);
-            while self.peek < self.source.len() && self.source[self.peek] != '\"' {
-                ident.push(self.source[self.peek]);
+        // Reads a string up to the closing ", which can be on a later line. Handles the escapes
+        // \n \t \\ \" \0 and \xNN, where NN is an ASCII code in hex
+        fn parse_string(&mut self) -> Result<(), Diagnostic> {
+            let start = self.start.0;
+            let mut s = String::new();
+            let unterminated = |l: &Lexer| l.error(start, "String without closing \"".to_string());
+            loop {
                 self.advance_token();
+                if self.pos >= self.source.len() {
+                    return Err(unterminated(self));
+                }
+                match self.char {
+                    '\"' => break,
+                    '\\' => match self.parse_escape() {
+                        Some(c) => s.push(c),
+                        None => return Err(unterminated(self)),
+                    },
+                    c => s.push(c),
+                }
             }
-            let s: String = ident.into_iter().collect();
             self.tokens.push(self.make_token(TokenType::STR(s)));
-            self.advance_token();
+            Ok(())
         }
 
-        pub fn parse_ident(&mut self) {
-            let mut ident = vec![];
-            ident.push(self.char);
-            while self.peek < self.source.len() && self.source[self.peek].is_alphabetic() {
-                ident.push(self.source[self.peek]);
-                self.advance_token();
+        // Reads the character after a \ in a string and returns the character it stands for;
+        // None if the source ends first. An invalid escape is reported and stands for itself
+        fn parse_escape(&mut self) -> Option<char> {
+            let start = self.byte;
+            self.advance_token();
+            if self.pos >= self.source.len() {
+                return None;
             }
-            let s = ident.into_iter().collect();
-            if let Some(t) = self.ident.get(&s) {
-                match t {
-                    TokenType::PEEK => self.tokens.push(self.make_token(TokenType::PEEK)),
-                    TokenType::DUP => self.tokens.push(self.make_token(TokenType::DUP)),
-                    TokenType::SWAP => self.tokens.push(self.make_token(TokenType::SWAP)),
-                    TokenType::NIP => self.tokens.push(self.make_token(TokenType::NIP)),
-                    TokenType::ROT => self.tokens.push(self.make_token(TokenType::ROT)),
-                    TokenType::DBG => self.tokens.push(self.make_token(TokenType::DBG)),
-                    TokenType::CHAR => self.tokens.push(self.make_token(TokenType::CHAR)),
-                    TokenType::IF(_) => self.tokens.push(self.make_token(TokenType::IF(0))),
-                    TokenType::ELSE(_) => self.tokens.push(self.make_token(TokenType::ELSE(0))),
-                    TokenType::WHILE(_, _) => self
-                        .tokens
-                        .push(self.make_token(TokenType::WHILE(Box::new(TokenType::EQUAL), 0))),
-                    TokenType::END(_, _) => self
-                        .tokens
-                        .push(self.make_token(TokenType::END(EndBlock::Cond, 0))),
-                    TokenType::SET => self.tokens.push(self.make_token(TokenType::SET)),
-                    TokenType::IDENT(s) => println!("Invalid ident: {:?} ({})", t, s),
-                    TokenType::INT(_) => println!("Invalid ident: {:?}", t),
-                    TokenType::STR(_) => println!("Invalid ident: {:?}", t),
-                    TokenType::PLUS => println!("Invalid ident: {:?}", t),
-                    TokenType::MINUS => println!("Invalid ident: {:?}", t),
-                    TokenType::ASTERISK => println!("Invalid ident: {:?}", t),
-                    TokenType::SLASH => println!("Invalid ident: {:?}", t),
-                    TokenType::PERIOD => println!("Invalid ident: {:?}", t),
-                    TokenType::COMMA => println!("Invalid ident: {:?}", t),
-                    TokenType::EQUAL => println!("Invalid ident: {:?}", t),
-                    TokenType::NEQUAL => println!("Invalid ident: {:?}", t),
-                    TokenType::LTE => println!("Invalid ident: {:?}", t),
-                    TokenType::LT => println!("Invalid ident: {:?}", t),
-                    TokenType::GTE => println!("Invalid ident: {:?}", t),
-                    TokenType::GT => println!("Invalid ident: {:?}", t),
-                    TokenType::QMARK => println!("Invalid ident: {:?}", t),
-                    TokenType::COLON => println!("Invalid ident: {:?}", t),
-                    TokenType::SEMICOLON => println!("Invalid ident: {:?}", t),
-                    TokenType::EM => println!("Invalid ident: {:?}", t),
-                    TokenType::LPAREN => println!("Invalid ident: {:?}", t),
-                    TokenType::RPAREN => println!("Invalid ident: {:?}", t),
-                    TokenType::EOF => println!("Invalid ident: {:?}", t),
+            let c = match self.char {
+                'n' => '\n',
+                't' => '\t',
+                '\\' => '\\',
+                '\"' => '\"',
+                '0' => '\0',
+                'x' => {
+                    let hex: String = self.source.iter().skip(self.pos + 1).take(2).collect();
+                    match u8::from_str_radix(&hex, 16) {
+                        Ok(b) if hex.chars().all(|c| c.is_ascii_hexdigit()) && b.is_ascii() => {
+                            self.advance_token();
+                            self.advance_token();
+                            b as char
+                        }
+                        _ => {
+                            let e = self.error(
+                                start,
+                                format!(
+                                    "Invalid escape \\x{}: expected two hex digits from 00 to 7f",
+                                    hex
+                                ),
+                            );
+                            self.errors.push(e);
+                            'x'
+                        }
+                    }
                 }
-            } else {
-                self.tokens.push(self.make_token(TokenType::IDENT(s)));
-            }
+                c => {
+                    let e = self.error(start, format!("Invalid escape \\{}", c));
+                    self.errors.push(e);
+                    c
+                }
+            };
+            Some(c)
         }
 
-        pub fn parse_number(&mut self) {
-            let mut num = vec![];
-            num.push(self.char);
-            while self.peek < self.source.len() && self.source[self.peek].is_digit(10) {
-                num.push(self.source[self.peek]);
+        // Reads a run of non-whitespace characters. Runs that are numbers become literals, and
+        // the rest are looked up in the ident table, so `+` is found the same way as `if`
+        fn parse_word(&mut self) {
+            let mut word = vec![];
+            word.push(self.char);
+            while self.peek() != '\0' && !self.peek().is_whitespace() && !self.peek().is_control() {
+                word.push(self.peek());
                 self.advance_token();
             }
-            let s: String = num.iter().collect();
-            let n = s.parse::<u32>();
-            if let Ok(i) = n {
-                self.tokens.push(self.make_token(TokenType::INT(i)))
+            let s: String = word.into_iter().collect();
+            // Words like 2dup are found before numbers
+            let after_colon =
+                matches!(self.tokens.last(), Some(t) if t.tok_type == TokenType::COLON);
+            let tok_type = match self.ident.get(&s) {
+                Some(t) => t.clone(),
+                None => match self.number(&s) {
+                    Ok(Some(t)) => t,
+                    // The name of a word being defined can look like a number, like 2x, and is
+                    // known by it from here on
+                    Ok(None) | Err(_) if after_colon => {
+                        self.define(&s);
+                        TokenType::IDENT(s)
+                    }
+                    Ok(None) => TokenType::IDENT(s),
+                    // Still pushes a number, so the parser doesn't report a missing value
+                    Err(e) => {
+                        self.errors.push(e);
+                        TokenType::INT(0)
+                    }
+                },
+            };
+            self.tokens.push(self.make_token(tok_type));
+        }
+
+        // Decimal, 0x hexadecimal or 0b binary, with an optional leading - and _ between digits;
+        // the prefix can also be 0X or 0B. Digits on both sides of a . make a float, like 1.5.
+        // Returns None for words that don't start with a digit, or a sign and a digit, and an
+        // error for ones that do but aren't numbers, like 0xfg, 1e5 or +1
+        fn number(&self, word: &str) -> Result<Option<TokenType>, Diagnostic> {
+            let body = word.strip_prefix(['-', '+']).unwrap_or(word);
+            if !body.starts_with(|c: char| c.is_ascii_digit()) {
+                return Ok(None);
+            }
+            let invalid =
+                || Err(self.error(self.start.0, format!("Invalid numeric literal: {}", word)));
+            if word.starts_with('+') {
+                return invalid();
+            }
+            let negative = word.starts_with('-');
+            if let Some((int, frac)) = body.split_once('.') {
+                let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+                if !digits(int) || !digits(frac) {
+                    return invalid();
+                }
+                let f: f64 = body.parse().unwrap();
+                return Ok(Some(TokenType::FLOAT(if negative { -f } else { f })));
+            }
+            let (radix, digits) = match body.get(..2) {
+                Some("0x" | "0X") => (16, &body[2..]),
+                Some("0b" | "0B") => (2, &body[2..]),
+                _ => (10, body),
+            };
+            let digits = digits.replace('_', "");
+            if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+                return invalid();
+            }
+            let digits = if negative {
+                format!("-{}", digits)
+            } else {
+                digits
+            };
+            match i64::from_str_radix(&digits, radix) {
+                Ok(n) => Ok(Some(TokenType::INT(n))),
+                Err(_) => Err(self.error(
+                    self.start.0,
+                    format!("Invalid number: {} doesn't fit in 64 bits", word),
+                )),
             }
         }
 
         fn peek(&self) -> char {
-            self.source[self.peek]
+            match self.source.get(self.peek) {
+                Some(c) => *c,
+                None => '\0',
+            }
         }
 
-        fn parse_comment(&mut self) {
-            let row = self.row;
-            let col = self.col;
+        fn parse_comment(&mut self) -> Result<(), Diagnostic> {
             if self.char == '(' {
+                // A comment right after : name is the word's signature
+                let sig = matches!(
+                    self.tokens.as_slice(),
+                    [.., a, b] if a.tok_type == TokenType::COLON
+                        && matches!(b.tok_type, TokenType::IDENT(_))
+                );
+                let mut text = vec![];
+                self.advance_token();
                 while self.char != ')' {
                     if self.char == '\0' {
-                        panic!(
-                            "{}",
-                            format!("{}:{}:{}: ( without closing )", self.source_file, row, col)
-                        );
+                        return Err(self.error(self.start.0, "( without closing )".to_string()));
                     }
 
+                    text.push(self.char);
                     self.advance_token();
                 }
+                if sig {
+                    let tok = self.make_token(TokenType::SIGNATURE(text.into_iter().collect()));
+                    self.tokens.push(tok);
+                }
             } else if self.char == '\\' {
-                while self.char != '\n' {
+                while self.peek() != '\n' && self.peek() != '\0' {
                     self.advance_token();
                 }
             }
+            Ok(())
+        }
+
+        pub fn set_include(&mut self, include: Vec<PathBuf>) {
+            self.include = include;
+        }
+
+        // Makes name lex as a word, even where it looks like a number; the repl gives each line
+        // the words defined before it
+        pub fn define(&mut self, name: &str) {
+            self.ident
+                .insert(name.to_string(), TokenType::IDENT(name.to_string()));
+        }
+
+        // Directories searched for an import, in order: the one holding this file, the include
+        // directories, the ones in RORTH_PATH, then ./std
+        fn import_dirs(&self) -> Vec<PathBuf> {
+            let mut dirs = vec![match Path::new(&*self.file).parent() {
+                Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                _ => PathBuf::from("."),
+            }];
+            dirs.extend(self.include.iter().cloned());
+            if let Some(paths) = env::var_os("RORTH_PATH") {
+                dirs.extend(env::split_paths(&paths).filter(|p| !p.as_os_str().is_empty()));
+            }
+            dirs.push(PathBuf::from("./std"));
+            dirs
+        }
+
+        // Path of the first name.rorth in the import directories; name can have directories
+        // of its own, like collections/list
+        fn resolve_import(&self, name: &str) -> Result<PathBuf, String> {
+            let file = format!("{}.rorth", name);
+            let dirs = self.import_dirs();
+            for dir in &dirs {
+                let path = dir.join(&file);
+                if path.is_file() {
+                    return Ok(path);
+                }
+            }
+            let dirs: Vec<String> = dirs.iter().map(|d| d.display().to_string()).collect();
+            Err(format!(
+                "Invalid import {}: {} not found in {}",
+                name,
+                file,
+                dirs.join(", ")
+            ))
+        }
+
+        // Names are made of letters, digits, _ and -, with / between directories
+        fn valid_import(name: &str) -> bool {
+            name.split('/').all(|part| {
+                !part.is_empty()
+                    && part
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+            })
         }
 
+        // -- a b ; puts the tokens of a.rorth and b.rorth in place of the import. A file
+        // already imported anywhere in the program is skipped, and a file importing one of the
+        // files that imports it is an error
         fn parse_imports(&mut self) {
-            let mut imports: Vec<String> = vec![];
+            let mut imports: Vec<(String, Span)> = vec![];
             self.advance_token();
             self.advance_token();
-            while self.char != ';' {
+            loop {
                 self.skip_space();
-                if !self.char.is_alphabetic() {
-                    panic!(
-                        "{}",
-                        format!(
-                            "{}:{}:{}: Invalid: {}",
-                            self.source_file, self.row, self.col, self.char
-                        )
-                    );
+                let start = self.byte;
+                if self.char == '\0' {
+                    let e = self.error(start, "Import without closing ;".to_string());
+                    self.errors.push(e);
+                    break;
+                }
+                if self.char == ';' {
+                    break;
                 }
-                let mut s: Vec<char> = vec![];
-                while self.char.is_alphabetic() {
+                let mut s = String::new();
+                while !self.char.is_whitespace() && self.char != ';' && self.char != '\0' {
                     s.push(self.char);
                     self.advance_token();
                 }
-                imports.push(s.into_iter().collect());
-                self.advance_token();
-                self.skip_space();
+                let span = Span {
+                    file: self.file.clone(),
+                    start,
+                    end: self.byte,
+                };
+                if !Self::valid_import(&s) {
+                    self.errors
+                        .push(diagnostic::error(&span, format!("Invalid import: {}", s)));
+                    continue;
+                }
+                imports.push((s, span));
             }
-            for i in imports {
-                let source_file = format!("./std/{}.rorth", i);
-                let program = fs::read_to_string(source_file.to_owned());
-                if let Err(_) = program {
-                    panic!("{}", format!("Invalid import: {}", i));
+
+            // Files are told apart by their canonical paths, so ./a and b/../a are the same
+            let canonical = |path: &Path| fs::canonicalize(path).ok();
+            let mut chain = self.chain.clone();
+            chain.push(self.file.to_string());
+            for (i, span) in imports {
+                let path = match self.resolve_import(&i) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        self.errors.push(diagnostic::error(&span, e));
+                        continue;
+                    }
+                };
+                let id = canonical(&path);
+                if let Some(pos) = chain
+                    .iter()
+                    .position(|f| id.is_some() && canonical(Path::new(f)) == id)
+                {
+                    let mut cycle = chain[pos..].to_vec();
+                    cycle.push(path.display().to_string());
+                    self.errors.push(diagnostic::error(
+                        &span,
+                        format!("Import cycle: {}", cycle.join(" -> ")),
+                    ));
+                    continue;
+                }
+                if let Some(id) = id {
+                    if !self.imported.insert(id) {
+                        continue;
+                    }
                 }
+                let program = match fs::read_to_string(&path) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        self.errors.push(diagnostic::error(
+                            &span,
+                            format!("Invalid import {}: {}: {}", i, path.display(), e),
+                        ));
+                        continue;
+                    }
+                };
 
-                let mut l = new(source_file.to_string(), program.unwrap());
+                // The imported file is lexed with what's been imported so far, and its errors
+                // and tokens become this file's. It's parsed along with this file
+                let mut l = new(path.display().to_string(), program);
+                l.set_include(self.include.clone());
+                l.chain = chain.clone();
+                l.imported = std::mem::take(&mut self.imported);
+                l.ident = std::mem::take(&mut self.ident);
                 if let Err(e) = l.lex() {
-                    panic!("{}", e);
-                }
-                let mut p = parser::new(source_file.to_string(), l.tokens);
-                if let Err(e) = p.parse() {
-                    panic!("{}", e);
+                    self.errors.extend(e);
                 }
-                for t in p.tokens {
+                self.imported = l.imported;
+                self.ident = l.ident;
+                for t in l.tokens {
                     if t.tok_type != TokenType::EOF {
                         self.tokens.push(t);
                     }
@@ -299,59 +575,46 @@ pub mod lexer {
             }
         }
 
-        pub fn lex(&mut self) -> Result<Vec<Token>, String> {
+        // Returns every error found if there are any; the tokens read are kept in tokens either
+        // way
+        pub fn lex(&mut self) -> Result<Vec<Token>, Vec<Diagnostic>> {
             while self.pos <= self.source.len() {
                 self.skip_space();
-                match self.char {
-                    '+' => self.tokens.push(self.make_token(TokenType::PLUS)),
-                    '*' => self.tokens.push(self.make_token(TokenType::ASTERISK)),
-                    '/' => self.tokens.push(self.make_token(TokenType::SLASH)),
-                    '.' => self.tokens.push(self.make_token(TokenType::PERIOD)),
-                    ',' => self.tokens.push(self.make_token(TokenType::COMMA)),
-                    '=' => self.tokens.push(self.make_token(TokenType::EQUAL)),
-                    '<' => self.tokens.push(self.make_token(TokenType::LT)),
-                    '>' => self.tokens.push(self.make_token(TokenType::GT)),
-                    '?' => self.tokens.push(self.make_token(TokenType::QMARK)),
-                    ';' => self.tokens.push(self.make_token(TokenType::SEMICOLON)),
+                self.start = (self.byte, self.row, self.col);
+                let res = match self.char {
+                    '\0' if self.pos >= self.source.len() => break,
                     '\"' => self.parse_string(),
                     '(' | '\\' => self.parse_comment(),
-                    ')' => self.tokens.push(self.make_token(TokenType::RPAREN)),
-                    '-' => {
-                        if self.peek() == '-' {
-                            if self.pos == 0 {
-                                self.parse_imports();
-                            } else {
-                                self.tokens.push(self.make_token(TokenType::EM));
-                                self.advance_token();
-                            }
-                        } else {
-                            self.tokens.push(self.make_token(TokenType::MINUS))
-                        }
+                    // -- on its own starts an import
+                    '-' if self.peek() == '-'
+                        && self
+                            .source
+                            .get(self.pos + 2)
+                            .is_none_or(|c| c.is_whitespace()) =>
+                    {
+                        self.parse_imports();
+                        Ok(())
                     }
-                    ':' => {
-                        if self.peek() == '=' {
-                            self.tokens.push(self.make_token(TokenType::SET));
-                            self.advance_token();
-                        } else {
-                            self.tokens.push(self.make_token(TokenType::COLON));
-                        }
+                    c if c.is_control() => {
+                        Err(self.error(self.start.0, format!("Invalid character {:?}", c)))
                     }
-                    '\0' => self.tokens.push(self.make_token(TokenType::EOF)),
                     _ => {
-                        if self.char.is_digit(10) {
-                            self.parse_number();
-                        } else if self.char.is_alphabetic() {
-                            self.parse_ident();
-                        } else {
-                            return Err(format!(
-                                "{}:{}:{}: Error lexing character: {}",
-                                self.source_file, self.row, self.col, self.char
-                            ));
-                        }
+                        self.parse_word();
+                        Ok(())
                     }
+                };
+                if let Err(e) = res {
+                    self.errors.push(e);
                 }
                 self.advance_token();
             }
+            // Errors that run to the end of the source stop the loop past it, so the EOF is
+            // pushed here for the parser to stop at
+            self.start = (self.byte, self.row, self.col);
+            self.tokens.push(self.make_token(TokenType::EOF));
+            if !self.errors.is_empty() {
+                return Err(self.errors.clone());
+            }
             Ok(self.tokens.to_vec())
         }
     }
@@ -1,6 +1,14 @@
+#![allow(clippy::module_inception)]
+
+pub mod ast;
 pub mod compiler;
+pub mod diagnostic;
+pub mod interpreter;
 pub mod lexer;
 pub mod op;
 pub mod parser;
 pub mod qbe_backend;
+pub mod repl;
+pub mod typechecker;
+pub mod value;
 pub mod vm;
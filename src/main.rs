@@ -1,49 +1,423 @@
-use std::{env, fs};
-
-use rorth::{lexer::lexer, parser::parser, qbe_backend};
-// use rorth::{compiler::compiler, vm::vm}
-
-// TODO: Fix using variables in loop conditional
-// TODO: Write interpreter
-// TODO: Write bytecode compiler and vm
-
-fn main() -> Result<(), String> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        return Err(format!("Please provide a file path"));
-    }
-
-    let file_path: Vec<&str> = args[1].split("/").collect();
-    let source_file = file_path[file_path.len() - 1];
-    let file_type = source_file.split(".").last();
-    if let Some("rorth") = file_type {
-        let program = fs::read_to_string(args[1].clone()).unwrap();
-
-        let mut l = lexer::new(source_file.to_string(), program);
-        if let Err(e) = l.lex() {
-            return Err(e);
-        }
-        let mut p = parser::new(source_file.to_string(), l.tokens);
-        if let Err(e) = p.parse() {
-            return Err(e);
-        }
-        let mut c = qbe_backend::qbe_backend::new(source_file.to_string(), p.tokens);
-        if let Err(e) = c.compile() {
-            return Err(e);
-        }
-        // let mut c = compiler::new(source_file.to_string(), p.tokens);
-        // if let Err(e) = c.compile() {
-        //     return Err(e);
-        // }
-        //
-        // let mut vm = vm::new(c.bytes, c.const_pool);
-        // if let Err(e) = vm.interpret() {
-        //     return Err(e);
-        // }
-    } else if let Some("rvm") = file_type {
-        return Err("Bytecode interpreter not yet implemented".to_string());
-    } else {
-        return Err(format!("Invalid file type: {:?}", file_type));
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use rorth::{
+    ast::ast::Program,
+    compiler::compiler,
+    diagnostic::diagnostic::Diagnostic,
+    interpreter::interpreter,
+    lexer::lexer,
+    parser::parser,
+    qbe_backend::qbe_backend::{self, BuildError, Options},
+    repl::repl,
+    typechecker::typechecker,
+    vm::vm,
+};
+
+const USAGE: &str = "\
+usage: rorth <command> [options] <file>
+
+commands:
+  run <file>      run a .rorth file, or a .rvm file on the vm
+  build <file>    compile to a native binary with qbe, or to bytecode with --backend vm
+  check <file>    lex, parse and type check without running
+  emit <file>     print --tokens, --ast, --ssa or --bytecode
+  disasm <file>   print the bytecode of a .rvm file
+  repl            read and run code a line at a time
+
+options:
+  --backend <b>       interp, vm or qbe
+  -o <path>           output of build
+  -I <dir>            search dir for imports, can be repeated
+  --verbose           print each step to stderr
+  --max-errors <n>    number of diagnostics printed, 20 by default";
+
+// Number of diagnostics printed when --max-errors isn't given
+const MAX_ERRORS: usize = 20;
+
+// Exit codes: errors in the program, bad arguments, a failure while the program runs, and a
+// file or tool that failed
+const EXIT_ERRORS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_RUNTIME: i32 = 3;
+const EXIT_IO: i32 = 4;
+
+// Everything that went wrong in a run and the code to exit with; lexing and parsing go on after
+// an error, every other stage stops at its first
+struct Errors {
+    code: i32,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl From<Vec<Diagnostic>> for Errors {
+    fn from(d: Vec<Diagnostic>) -> Errors {
+        Errors {
+            code: EXIT_ERRORS,
+            diagnostics: d,
+        }
+    }
+}
+
+impl From<Diagnostic> for Errors {
+    fn from(d: Diagnostic) -> Errors {
+        vec![d].into()
+    }
+}
+
+impl From<BuildError> for Errors {
+    fn from(e: BuildError) -> Errors {
+        match e {
+            BuildError::Program(d) => d.into(),
+            BuildError::Tool(e) => fail(EXIT_IO, e),
+            BuildError::Run(e) => fail(EXIT_RUNTIME, e),
+        }
+    }
+}
+
+fn fail(code: i32, d: impl Into<Diagnostic>) -> Errors {
+    Errors {
+        code,
+        diagnostics: vec![d.into()],
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Backend {
+    Interp,
+    Vm,
+    Qbe,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    Tokens,
+    Ast,
+    Ssa,
+    Bytecode,
+}
+
+struct Args {
+    command: String,
+    file: String,
+    output: Option<String>,
+    backend: Option<Backend>,
+    emit: Option<Emit>,
+    include: Vec<PathBuf>,
+    verbose: bool,
+    max_errors: usize,
+}
+
+impl Args {
+    // Prints a step of the build to stderr when --verbose is given
+    fn step(&self, msg: String) {
+        if self.verbose {
+            eprintln!("rorth: {}", msg);
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut a = Args {
+        command: String::new(),
+        file: String::new(),
+        output: None,
+        backend: None,
+        emit: None,
+        include: vec![],
+        verbose: false,
+        max_errors: MAX_ERRORS,
+    };
+    let mut files: Vec<String> = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        // Flags that take a value, written as -o x or --flag x; -I also takes -Idir
+        let mut value = |name: &str| -> Result<String, String> {
+            i += 1;
+            match args.get(i) {
+                Some(v) => Ok(v.clone()),
+                None => Err(format!("{} expects a value", name)),
+            }
+        };
+        match arg {
+            "--backend" => {
+                a.backend = Some(match value(arg)?.as_str() {
+                    "interp" => Backend::Interp,
+                    "vm" => Backend::Vm,
+                    "qbe" => Backend::Qbe,
+                    b => return Err(format!("Unknown backend {}", b)),
+                })
+            }
+            "-o" => a.output = Some(value(arg)?),
+            "-I" => a.include.push(PathBuf::from(value(arg)?)),
+            "--max-errors" => match value(arg)?.parse() {
+                Ok(n) => a.max_errors = n,
+                Err(_) => return Err("--max-errors expects a number".to_string()),
+            },
+            "--verbose" => a.verbose = true,
+            "--tokens" | "--ast" | "--ssa" | "--bytecode" => {
+                if a.emit.is_some() {
+                    return Err(
+                        "emit takes one of --tokens, --ast, --ssa or --bytecode".to_string()
+                    );
+                }
+                a.emit = Some(match arg {
+                    "--tokens" => Emit::Tokens,
+                    "--ast" => Emit::Ast,
+                    "--ssa" => Emit::Ssa,
+                    _ => Emit::Bytecode,
+                })
+            }
+            _ if arg.starts_with("-I") => a.include.push(PathBuf::from(&arg[2..])),
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("Unknown option {}", arg))
+            }
+            _ if a.command.is_empty() => a.command = arg.to_string(),
+            _ => files.push(arg.to_string()),
+        }
+        i += 1;
+    }
+
+    match a.command.as_str() {
+        "" => return Err("Please provide a command".to_string()),
+        "run" | "build" | "check" | "emit" | "disasm" | "repl" => {}
+        c => return Err(format!("Unknown command {}", c)),
+    }
+    a.file = match files.as_slice() {
+        [] if a.command == "repl" => String::new(),
+        _ if a.command == "repl" => return Err("repl doesn't take a file".to_string()),
+        [f] => f.clone(),
+        [] => return Err("Please provide a file path".to_string()),
+        _ => return Err(format!("Expected one file, got {}", files.join(" "))),
+    };
+    if a.output.is_some() && a.command != "build" {
+        return Err("-o is only used by build".to_string());
+    }
+    if a.emit.is_some() != (a.command == "emit") {
+        return Err("--tokens, --ast, --ssa and --bytecode go with emit".to_string());
+    }
+    Ok(a)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(EXIT_USAGE);
+    }
+    if args
+        .iter()
+        .any(|a| a == "-h" || a == "--help" || a == "help")
+    {
+        println!("{}", USAGE);
+        return;
+    }
+    let args = match parse_args(&args) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    if let Err(Errors { code, diagnostics }) = run(&args) {
+        // Diagnostics point into the file they name, which is read again to show the line
+        for d in diagnostics.iter().take(args.max_errors) {
+            let source = fs::read_to_string(&d.file).ok();
+            eprint!("{}", d.render(source.as_deref()));
+        }
+        if diagnostics.len() > args.max_errors {
+            eprintln!(
+                "... and {} more errors",
+                diagnostics.len() - args.max_errors
+            );
+        }
+        process::exit(code);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Errors> {
+    match args.command.as_str() {
+        "run" => run_file(args),
+        "build" => build(args),
+        "check" => {
+            parse_file(args)?;
+            args.step(format!("{} is ok", args.file));
+            Ok(())
+        }
+        "emit" => emit(args),
+        "repl" => {
+            let mut r = repl::new(args.include.clone());
+            r.run().map_err(|e| fail(EXIT_IO, e.to_string()))
+        }
+        _ => {
+            let mut vm = load_rvm(&args.file)?;
+            vm.disassemble().map_err(|e| fail(EXIT_ERRORS, e))?;
+            Ok(())
+        }
+    }
+}
+
+fn read(path: &str) -> Result<String, Errors> {
+    fs::read_to_string(path).map_err(|e| fail(EXIT_IO, format!("Error reading {}: {}", path, e)))
+}
+
+// Lexes a source file, searching the -I dirs for its imports
+fn lex_file(args: &Args) -> Result<lexer::Lexer, Errors> {
+    let mut l = lexer::new(args.file.clone(), read(&args.file)?);
+    l.set_include(args.include.clone());
+    args.step(format!("lexing {}", args.file));
+    Ok(l)
+}
+
+// Lexes, parses and type checks a source file. The tokens are parsed even if lexing failed, so
+// the errors of both are reported
+fn parse_file(args: &Args) -> Result<Program, Errors> {
+    let mut l = lex_file(args)?;
+    let mut errors = l.lex().err().unwrap_or_default();
+    let mut p = parser::new(l.tokens);
+    match p.parse() {
+        Ok(program) if errors.is_empty() => {
+            args.step("type checking".to_string());
+            typechecker::new().check(&program)?;
+            Ok(program)
+        }
+        Ok(_) => Err(errors.into()),
+        Err(e) => {
+            errors.extend(e);
+            Err(errors.into())
+        }
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(path.to_string(), |n| n.to_string_lossy().to_string())
+}
+
+fn file_stem(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map_or("rorth".to_string(), |n| n.to_string_lossy().to_string())
+}
+
+fn load_rvm(path: &str) -> Result<vm::VM, Errors> {
+    let bytes =
+        fs::read(path).map_err(|e| fail(EXIT_IO, format!("Error reading {}: {}", path, e)))?;
+    vm::load(&bytes).map_err(|e| fail(EXIT_ERRORS, format!("{}: {}", path, e)))
+}
+
+fn compile_vm(args: &Args, program: &Program) -> Result<compiler::Compiler, Errors> {
+    args.step("compiling to bytecode".to_string());
+    let mut c = compiler::new();
+    c.compile(program)?;
+    Ok(c)
+}
+
+fn run_file(args: &Args) -> Result<(), Errors> {
+    // A .rvm file is bytecode that's already been compiled
+    if args.file.ends_with(".rvm") {
+        if !matches!(args.backend, None | Some(Backend::Vm)) {
+            return Err(fail(
+                EXIT_USAGE,
+                ".rvm files only run on the vm".to_string(),
+            ));
+        }
+        let mut vm = load_rvm(&args.file)?;
+        args.step(format!("running {} on the vm", args.file));
+        vm.interpret().map_err(|e| fail(EXIT_RUNTIME, e))?;
+        println!();
+        return Ok(());
+    }
+    let program = parse_file(args)?;
+    match args.backend.unwrap_or(Backend::Interp) {
+        Backend::Interp => {
+            args.step("interpreting".to_string());
+            let mut i = interpreter::new();
+            i.interpret(program).map_err(|e| fail(EXIT_RUNTIME, e))?;
+        }
+        Backend::Vm => {
+            let c = compile_vm(args, &program)?;
+            let (code, debug_info) = c.assemble();
+            let mut vm = vm::new(code, c.const_pool);
+            vm.set_debug(debug_info);
+            args.step("running on the vm".to_string());
+            vm.interpret().map_err(|e| fail(EXIT_RUNTIME, e))?;
+        }
+        Backend::Qbe => {
+            let opts = Options {
+                bin_name: file_stem(&args.file),
+                run: true,
+                ..Default::default()
+            };
+            qbe_backend::build(&program, &opts, &|msg| args.step(msg))?;
+        }
+    }
+    println!();
+    Ok(())
+}
+
+fn build(args: &Args) -> Result<(), Errors> {
+    let program = parse_file(args)?;
+    match args.backend.unwrap_or(Backend::Qbe) {
+        Backend::Interp => Err(fail(
+            EXIT_USAGE,
+            "The interpreter has nothing to build, use --backend vm or qbe".to_string(),
+        )),
+        Backend::Vm => {
+            let c = compile_vm(args, &program)?;
+            let out = match &args.output {
+                Some(o) => o.clone(),
+                None => format!("{}.rvm", file_stem(&args.file)),
+            };
+            c.write_rvm(&out, true).map_err(|e| fail(EXIT_IO, e))?;
+            args.step(format!("wrote {}", out));
+            Ok(())
+        }
+        Backend::Qbe => {
+            // -o dir/prog puts the binary at dir/prog; without it, it goes in ./out
+            let mut opts = Options {
+                bin_name: file_stem(&args.file),
+                ..Default::default()
+            };
+            if let Some(o) = &args.output {
+                let o = Path::new(o);
+                opts.bin_name = file_name(&o.to_string_lossy());
+                opts.out_dir = match o.parent() {
+                    Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                    _ => PathBuf::from("."),
+                };
+            }
+            qbe_backend::build(&program, &opts, &|msg| args.step(msg))?;
+            Ok(())
+        }
+    }
+}
+
+fn emit(args: &Args) -> Result<(), Errors> {
+    match args.emit {
+        Some(Emit::Tokens) => {
+            let mut l = lex_file(args)?;
+            let res = l.lex();
+            l.print();
+            res?;
+        }
+        Some(Emit::Ast) => println!("{:#?}", parse_file(args)?),
+        Some(Emit::Ssa) => {
+            let program = parse_file(args)?;
+            print!("{}", qbe_backend::new().compile(&program)?);
+        }
+        _ => {
+            let program = parse_file(args)?;
+            let c = compile_vm(args, &program)?;
+            let (code, debug_info) = c.assemble();
+            let mut vm = vm::new(code, c.const_pool);
+            vm.set_debug(debug_info);
+            vm.disassemble().map_err(|e| fail(EXIT_ERRORS, e))?;
+        }
     }
     Ok(())
 }
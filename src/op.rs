@@ -1,47 +1,374 @@
 pub mod op {
-    #[derive(Debug, Clone, Copy)]
+    // Header of a serialized .rvm file
+    pub const RVM_MAGIC: &[u8; 4] = b"RVM\0";
+    pub const RVM_VERSION: u16 = 4;
+    // Set in the header flags when the file ends with a debug section
+    pub const RVM_FLAG_DEBUG: u16 = 0x01;
+
+    // Opcode of each op: the first byte of its encoding and its discriminant
+    pub mod opcode {
+        pub const NOOP: u8 = 0x00;
+        pub const PUSHNUM: u8 = 0x01;
+        pub const PUSHSTR: u8 = 0x11;
+        pub const PUSHFLT: u8 = 0x41;
+        pub const DROP: u8 = 0x21;
+        pub const DUP: u8 = 0x31;
+        pub const SWAP: u8 = 0x02;
+        pub const NIP: u8 = 0x12;
+        pub const ROT: u8 = 0x03;
+        pub const OVER: u8 = 0x22;
+        pub const TWODUP: u8 = 0x32;
+        pub const TWODROP: u8 = 0x42;
+        pub const TUCK: u8 = 0x13;
+        pub const PICK: u8 = 0x23;
+        pub const DEPTH: u8 = 0x33;
+        pub const ADD: u8 = 0x04;
+        pub const SUB: u8 = 0x14;
+        pub const MUL: u8 = 0x24;
+        pub const DIV: u8 = 0x34;
+        pub const MOD: u8 = 0x44;
+        pub const ITOF: u8 = 0x0a;
+        pub const FTOI: u8 = 0x1a;
+        pub const EQ: u8 = 0x05;
+        pub const NE: u8 = 0x15;
+        pub const LT: u8 = 0x25;
+        pub const LE: u8 = 0x35;
+        pub const GT: u8 = 0x45;
+        pub const GE: u8 = 0x55;
+        pub const AND: u8 = 0x09;
+        pub const OR: u8 = 0x19;
+        pub const NOT: u8 = 0x29;
+        pub const JMP: u8 = 0x06;
+        pub const JZ: u8 = 0x16;
+        pub const JNZ: u8 = 0x26;
+        pub const LOAD: u8 = 0x07;
+        pub const STORE: u8 = 0x17;
+        pub const CALL: u8 = 0x08;
+        pub const RET: u8 = 0x18;
+        pub const DBG: u8 = 0xde;
+        pub const CONST: u8 = 0xee;
+        pub const PRINT: u8 = 0x0f;
+        pub const PEEK: u8 = 0x1f;
+        pub const PRINTC: u8 = 0x2f;
+        pub const HALT: u8 = 0xff;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     #[repr(u8)]
     pub enum Op {
-        NOOP = 0x00,
-        PUSHNUM(usize) = 0x01,
-        PUSHSTR(usize) = 0x11,
-        DROP = 0x21,
-        DUP = 0x31,
-        SWAP = 0x02,
-        NIP = 0x12,
-        ROT = 0x03,
-        ADD = 0x04,
-        SUB = 0x14,
-        MUL = 0x24,
-        DIV = 0x34,
-        DBG = 0xde,
-        CONST(i64) = 0xee,
-        PRINTI = 0x0f,
-        PRINTS = 0x2f,
-        HALT = 0xff,
+        NOOP = opcode::NOOP,
+        PUSHNUM(usize) = opcode::PUSHNUM,
+        PUSHSTR(usize) = opcode::PUSHSTR,
+        // Pushes the float whose bits are stored in the constant pool
+        PUSHFLT(usize) = opcode::PUSHFLT,
+        DROP = opcode::DROP,
+        DUP = opcode::DUP,
+        SWAP = opcode::SWAP,
+        NIP = opcode::NIP,
+        ROT = opcode::ROT,
+        OVER = opcode::OVER,
+        TWODUP = opcode::TWODUP,
+        TWODROP = opcode::TWODROP,
+        TUCK = opcode::TUCK,
+        // Pops n and copies the value n below the top
+        PICK = opcode::PICK,
+        // Pushes the number of values on the stack
+        DEPTH = opcode::DEPTH,
+        ADD = opcode::ADD,
+        SUB = opcode::SUB,
+        MUL = opcode::MUL,
+        DIV = opcode::DIV,
+        MOD = opcode::MOD,
+        // Conversions between ints and floats
+        ITOF = opcode::ITOF,
+        FTOI = opcode::FTOI,
+        // Comparisons leave both operands on the stack and push 1 or 0
+        EQ = opcode::EQ,
+        NE = opcode::NE,
+        LT = opcode::LT,
+        LE = opcode::LE,
+        GT = opcode::GT,
+        GE = opcode::GE,
+        // Logical operators pop their operands and push 1 or 0
+        AND = opcode::AND,
+        OR = opcode::OR,
+        NOT = opcode::NOT,
+        // Jump targets are indices into the op vector, or byte offsets once encoded
+        JMP(usize) = opcode::JMP,
+        JZ(usize) = opcode::JZ,
+        JNZ(usize) = opcode::JNZ,
+        // Operands are indices into the variable slots of the current call frame
+        LOAD(usize) = opcode::LOAD,
+        STORE(usize) = opcode::STORE,
+        // Calls the word starting at the target, which returns with RET
+        CALL(usize) = opcode::CALL,
+        RET = opcode::RET,
+        DBG = opcode::DBG,
+        CONST(i64) = opcode::CONST,
+        PRINT = opcode::PRINT,
+        PEEK = opcode::PEEK,
+        PRINTC = opcode::PRINTC,
+        HALT = opcode::HALT,
+    }
+
+    impl Op {
+        // First byte of the encoded op
+        pub fn opcode(&self) -> u8 {
+            match self {
+                Op::NOOP => opcode::NOOP,
+                Op::PUSHNUM(_) => opcode::PUSHNUM,
+                Op::PUSHSTR(_) => opcode::PUSHSTR,
+                Op::PUSHFLT(_) => opcode::PUSHFLT,
+                Op::DROP => opcode::DROP,
+                Op::DUP => opcode::DUP,
+                Op::SWAP => opcode::SWAP,
+                Op::NIP => opcode::NIP,
+                Op::ROT => opcode::ROT,
+                Op::OVER => opcode::OVER,
+                Op::TWODUP => opcode::TWODUP,
+                Op::TWODROP => opcode::TWODROP,
+                Op::TUCK => opcode::TUCK,
+                Op::PICK => opcode::PICK,
+                Op::DEPTH => opcode::DEPTH,
+                Op::ADD => opcode::ADD,
+                Op::SUB => opcode::SUB,
+                Op::MUL => opcode::MUL,
+                Op::DIV => opcode::DIV,
+                Op::MOD => opcode::MOD,
+                Op::ITOF => opcode::ITOF,
+                Op::FTOI => opcode::FTOI,
+                Op::EQ => opcode::EQ,
+                Op::NE => opcode::NE,
+                Op::LT => opcode::LT,
+                Op::LE => opcode::LE,
+                Op::GT => opcode::GT,
+                Op::GE => opcode::GE,
+                Op::AND => opcode::AND,
+                Op::OR => opcode::OR,
+                Op::NOT => opcode::NOT,
+                Op::JMP(_) => opcode::JMP,
+                Op::JZ(_) => opcode::JZ,
+                Op::JNZ(_) => opcode::JNZ,
+                Op::LOAD(_) => opcode::LOAD,
+                Op::STORE(_) => opcode::STORE,
+                Op::CALL(_) => opcode::CALL,
+                Op::RET => opcode::RET,
+                Op::DBG => opcode::DBG,
+                Op::CONST(_) => opcode::CONST,
+                Op::PRINT => opcode::PRINT,
+                Op::PEEK => opcode::PEEK,
+                Op::PRINTC => opcode::PRINTC,
+                Op::HALT => opcode::HALT,
+            }
+        }
     }
 
     impl std::fmt::Display for Op {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:02x}", self.opcode())?;
             match self {
-                Op::NOOP => write!(f, "00"),
-                Op::PUSHNUM(n) => write!(f, "01 {:02x}", n),
-                Op::PUSHSTR(n) => write!(f, "11 {:02x}", n),
-                Op::SWAP => write!(f, "02"),
-                Op::ROT => write!(f, "03"),
-                Op::ADD => write!(f, "04"),
-                Op::SUB => write!(f, "14"),
-                Op::MUL => write!(f, "24"),
-                Op::DIV => write!(f, "34"),
-                Op::PRINTI => write!(f, "0f"),
-                Op::PRINTS => write!(f, "2f"),
-                Op::NIP => write!(f, "12"),
-                Op::DROP => write!(f, "21"),
-                Op::DUP => write!(f, "31"),
-                Op::DBG => write!(f, "de"),
-                Op::HALT => write!(f, "ff"),
-                Op::CONST(_) => todo!(),
+                Op::PUSHNUM(n)
+                | Op::PUSHSTR(n)
+                | Op::PUSHFLT(n)
+                | Op::JMP(n)
+                | Op::JZ(n)
+                | Op::JNZ(n)
+                | Op::LOAD(n)
+                | Op::STORE(n)
+                | Op::CALL(n) => write!(f, " {:02x}", n),
+                Op::CONST(n) => write!(f, " {:02x}", n),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    pub fn write_uleb(out: &mut Vec<u8>, mut n: u64) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(byte);
+                return;
             }
+            out.push(byte | 0x80);
         }
     }
+
+    pub fn write_sleb(out: &mut Vec<u8>, mut n: i64) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    pub fn read_uleb(code: &[u8], pos: &mut usize) -> Result<u64, String> {
+        let mut n: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = read_byte(code, pos)?;
+            if shift >= 64 {
+                return Err(format!("Invalid operand: LEB128 value too long at {}", pos));
+            }
+            n |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+    }
+
+    pub fn read_sleb(code: &[u8], pos: &mut usize) -> Result<i64, String> {
+        let mut n: i64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = read_byte(code, pos)?;
+            if shift >= 64 {
+                return Err(format!("Invalid operand: LEB128 value too long at {}", pos));
+            }
+            n |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    n |= -1 << shift;
+                }
+                return Ok(n);
+            }
+        }
+    }
+
+    fn read_byte(code: &[u8], pos: &mut usize) -> Result<u8, String> {
+        match code.get(*pos) {
+            Some(b) => {
+                *pos += 1;
+                Ok(*b)
+            }
+            None => Err(format!(
+                "Invalid bytecode: unexpected end of code at {}",
+                pos
+            )),
+        }
+    }
+
+    // Jump and call targets are written as fixed width u32 byte offsets so every op's size is
+    // known before the targets are resolved; all other operands are LEB128
+    const JUMP_WIDTH: usize = 4;
+
+    fn encoded_len(op: &Op) -> usize {
+        let mut buf = vec![];
+        match *op {
+            Op::JMP(_) | Op::JZ(_) | Op::JNZ(_) | Op::CALL(_) => return 1 + JUMP_WIDTH,
+            Op::PUSHNUM(n) | Op::PUSHSTR(n) | Op::PUSHFLT(n) | Op::LOAD(n) | Op::STORE(n) => {
+                write_uleb(&mut buf, n as u64)
+            }
+            Op::CONST(n) => write_sleb(&mut buf, n),
+            _ => {}
+        }
+        1 + buf.len()
+    }
+
+    // Encodes ops whose jump targets are op indices into bytes whose jump targets are byte
+    // offsets. Also returns the byte offset each op starts at
+    pub fn encode(ops: &[Op]) -> (Vec<u8>, Vec<usize>) {
+        let mut offsets = Vec::with_capacity(ops.len() + 1);
+        let mut offset = 0;
+        for op in ops {
+            offsets.push(offset);
+            offset += encoded_len(op);
+        }
+        // A jump may target the end of the code
+        offsets.push(offset);
+
+        let mut code = Vec::with_capacity(offset);
+        for op in ops {
+            code.push(op.opcode());
+            match *op {
+                Op::JMP(n) | Op::JZ(n) | Op::JNZ(n) | Op::CALL(n) => {
+                    code.extend_from_slice(&(offsets[n] as u32).to_le_bytes())
+                }
+                Op::PUSHNUM(n) | Op::PUSHSTR(n) | Op::PUSHFLT(n) | Op::LOAD(n) | Op::STORE(n) => {
+                    write_uleb(&mut code, n as u64)
+                }
+                Op::CONST(n) => write_sleb(&mut code, n),
+                _ => {}
+            }
+        }
+        offsets.pop();
+        (code, offsets)
+    }
+
+    // Decodes the op starting at pos and moves pos past it
+    pub fn decode(code: &[u8], pos: &mut usize) -> Result<Op, String> {
+        let start = *pos;
+        let opcode = read_byte(code, pos)?;
+        let op = match opcode {
+            opcode::NOOP => Op::NOOP,
+            opcode::PUSHNUM => Op::PUSHNUM(read_uleb(code, pos)? as usize),
+            opcode::PUSHSTR => Op::PUSHSTR(read_uleb(code, pos)? as usize),
+            opcode::PUSHFLT => Op::PUSHFLT(read_uleb(code, pos)? as usize),
+            opcode::DROP => Op::DROP,
+            opcode::DUP => Op::DUP,
+            opcode::SWAP => Op::SWAP,
+            opcode::NIP => Op::NIP,
+            opcode::ROT => Op::ROT,
+            opcode::OVER => Op::OVER,
+            opcode::TWODUP => Op::TWODUP,
+            opcode::TWODROP => Op::TWODROP,
+            opcode::TUCK => Op::TUCK,
+            opcode::PICK => Op::PICK,
+            opcode::DEPTH => Op::DEPTH,
+            opcode::ADD => Op::ADD,
+            opcode::SUB => Op::SUB,
+            opcode::MUL => Op::MUL,
+            opcode::DIV => Op::DIV,
+            opcode::MOD => Op::MOD,
+            opcode::ITOF => Op::ITOF,
+            opcode::FTOI => Op::FTOI,
+            opcode::EQ => Op::EQ,
+            opcode::NE => Op::NE,
+            opcode::LT => Op::LT,
+            opcode::LE => Op::LE,
+            opcode::GT => Op::GT,
+            opcode::GE => Op::GE,
+            opcode::AND => Op::AND,
+            opcode::OR => Op::OR,
+            opcode::NOT => Op::NOT,
+            opcode::JMP | opcode::JZ | opcode::JNZ | opcode::CALL => {
+                if *pos + JUMP_WIDTH > code.len() {
+                    return Err(format!(
+                        "Invalid bytecode: unexpected end of code at {}",
+                        pos
+                    ));
+                }
+                let n = u32::from_le_bytes(code[*pos..*pos + JUMP_WIDTH].try_into().unwrap());
+                *pos += JUMP_WIDTH;
+                match opcode {
+                    opcode::JMP => Op::JMP(n as usize),
+                    opcode::JZ => Op::JZ(n as usize),
+                    opcode::JNZ => Op::JNZ(n as usize),
+                    _ => Op::CALL(n as usize),
+                }
+            }
+            opcode::LOAD => Op::LOAD(read_uleb(code, pos)? as usize),
+            opcode::STORE => Op::STORE(read_uleb(code, pos)? as usize),
+            opcode::RET => Op::RET,
+            opcode::DBG => Op::DBG,
+            opcode::CONST => Op::CONST(read_sleb(code, pos)?),
+            opcode::PRINT => Op::PRINT,
+            opcode::PEEK => Op::PEEK,
+            opcode::PRINTC => Op::PRINTC,
+            opcode::HALT => Op::HALT,
+            _ => {
+                return Err(format!(
+                    "Invalid bytecode: unknown opcode {:02x} at {}",
+                    opcode, start
+                ))
+            }
+        };
+        Ok(op)
+    }
 }
@@ -1,44 +1,68 @@
 pub mod parser {
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
 
-    use crate::lexer::lexer::{EndBlock, Token, TokenType};
+    use crate::{
+        ast::ast::{Block, Builtin, Literal, Node, NodeKind, Program, Signature, WordDef},
+        diagnostic::diagnostic::{self, Diagnostic},
+        lexer::lexer::{Token, TokenType},
+    };
 
+    // Deepest nesting of blocks the parser accepts. The parser, the type checker and the
+    // backends recurse once per level, and a debug build overflows the 8 MiB main stack at
+    // about 900 levels, so this leaves room for blocks inside called words
+    pub const MAX_DEPTH: usize = 256;
+
+    #[derive(Clone)]
     pub struct Parser {
         pos: usize,
-        peek: usize,
-        source_file: String,
+        // Depth of the stack in the code being parsed
         stack: i32,
-        var_stack: Vec<String>,
+        // After a block whose branches or iterations leave different depths, or a call to a word
+        // without a fixed stack effect, the depth depends on the data, so from there on
+        // underflow is only caught at runtime
+        stack_known: bool,
+        // Set when parsing a word without a signature; instead of underflowing, the word takes
+        // the values it's missing from its caller
+        inferring: bool,
+        // Lowest depth reached while inferring; the word takes -low values
+        low: i32,
+        // HashMap in the form "word name": (values taken, values left), if known
+        words: HashMap<String, Option<(i32, i32)>>,
+        // Set containing the names of the variables of the word (or program) being parsed
         vars: HashSet<String>,
-        if_stack: i32,
-        else_stack: i32,
-        loop_stack: i32,
-        if_end_stack: i32,
-        loop_end_stack: i32,
-        cur_block: EndBlock,
-        parse_inside: bool,
+        // Name of the last variable referenced; target of the next :=
+        last_var: Option<String>,
+        // Blocks that are still open, innermost last, with the tokens that can close them
+        blocks: Vec<(Token, Vec<TokenType>)>,
         pub tokens: Vec<Token>,
+        // Errors found so far; after each one the parser skips ahead with recover
+        errors: Vec<Diagnostic>,
     }
 
-    pub fn new(source: String, tokens: Vec<Token>) -> Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
         Parser {
             pos: 0,
-            peek: 1,
-            source_file: source,
             stack: 0,
-            var_stack: vec![],
+            stack_known: true,
+            inferring: false,
+            low: 0,
+            words: HashMap::new(),
             vars: HashSet::new(),
-            if_stack: 0,
-            else_stack: 0,
-            loop_stack: 0,
-            if_end_stack: 0,
-            loop_end_stack: 0,
-            cur_block: EndBlock::Cond,
-            parse_inside: true,
+            last_var: None,
+            blocks: vec![],
             tokens,
+            errors: vec![],
         }
     }
 
+    // Tokens that end a word or a block
+    fn is_closer(t: &TokenType) -> bool {
+        matches!(
+            t,
+            TokenType::SEMICOLON | TokenType::END | TokenType::ELSE | TokenType::REPEAT
+        )
+    }
+
     impl Parser {
         pub fn print(&self) {
             for i in &self.tokens {
@@ -46,284 +70,521 @@ pub mod parser {
             }
         }
 
+        // Gets ready to parse more tokens with the words and variables of what was parsed
+        // before, on a stack that's depth values deep; the repl parses each line this way
+        pub fn resume(&mut self, tokens: Vec<Token>, depth: usize) {
+            self.tokens = tokens;
+            self.pos = 0;
+            self.set_depth(Some(depth as i32));
+            self.inferring = false;
+            self.low = 0;
+            self.blocks.clear();
+            self.errors.clear();
+        }
+
+        fn format_err(&self, tok: &Token, message: String) -> Diagnostic {
+            diagnostic::error(&tok.span, message)
+        }
+
+        // Stops at the last token, the EOF, so nothing reads past the end
         fn advance_token(&mut self) {
-            self.pos += 1;
-            self.peek += 1;
+            if self.pos + 1 < self.tokens.len() {
+                self.pos += 1;
+            }
+        }
+
+        fn next_type(&self) -> TokenType {
+            match self.tokens.get(self.pos + 1) {
+                Some(t) => t.tok_type.clone(),
+                None => TokenType::EOF,
+            }
+        }
+
+        fn node(tok: &Token, kind: NodeKind) -> Node {
+            Node {
+                kind,
+                row: tok.row,
+                col: tok.col,
+                span: tok.span.clone(),
+            }
         }
 
-        fn stack_overflow(&mut self, tok: &Token, req: i32, change: i32) -> Result<i32, String> {
-            if self.parse_inside {
-                if self.stack < req {
-                    return Err(format!(
-                        "{}:{}:{}: Invalid {:?}: Not enough values on the stack",
-                        self.source_file, tok.row, tok.col, tok.tok_type
+        // Checks that there are at least req values on the stack, then applies change
+        fn stack_overflow(&mut self, tok: &Token, req: i32, change: i32) -> Result<(), Diagnostic> {
+            if !self.stack_known {
+                return Ok(());
+            }
+            if self.stack < req {
+                if !self.inferring {
+                    return Err(self.format_err(
+                        tok,
+                        format!("Invalid {:?}: Not enough values on the stack", tok.tok_type),
                     ));
                 }
-                return Ok(self.stack + change);
-            } else {
-                return Ok(self.stack);
+                self.low = self.low.min(self.stack - req);
             }
+            self.stack += change;
+            Ok(())
+        }
+
+        // Depth of the stack, or None if it depends on the data
+        fn depth(&self) -> Option<i32> {
+            self.stack_known.then_some(self.stack)
         }
 
-        fn parse_ident(&mut self, var: &String) -> Result<i32, String> {
-            match self.vars.insert(var.to_string()) {
-                true => {
-                    self.var_stack.push(var.to_string());
-                    Ok(self.stack)
+        fn set_depth(&mut self, depth: Option<i32>) {
+            match depth {
+                Some(d) => {
+                    self.stack = d;
+                    self.stack_known = true;
                 }
-                false => Ok(self.stack + 1),
+                None => self.stack_known = false,
             }
         }
 
-        fn parse_set(&mut self) -> Result<i32, String> {
-            let var = self.var_stack.last();
-            if let Some(_) = var {
-                return Ok(self.stack - 1);
-            } else {
-                return Err("No variable to assign to".to_string());
+        // Net change in depth of a single node, used for the condition of a while ... end loop
+        fn node_effect(&self, node: &Node) -> Option<i32> {
+            match &node.kind {
+                NodeKind::Literal(_) | NodeKind::Var(_) => Some(1),
+                NodeKind::Assign(_) => Some(-1),
+                NodeKind::Builtin(b) => {
+                    let (req, out) = b.effect();
+                    Some(out - req)
+                }
+                NodeKind::Call(w) => self.words.get(w).copied().flatten().map(|(i, o)| o - i),
+                _ => None,
             }
         }
 
-        fn parse_if_block(&mut self, tok: &Token, req: i32, change: i32) -> Result<Token, String> {
-            let err = self.stack_overflow(tok, req, change);
-            match err {
-                Ok(i) => {
-                    if i < 0 {
-                        panic!(
-                            "{}",
-                            format!(
-                                "{}:{}:{}: Invalid {:?}: Nothing on the stack to compare",
-                                self.source_file, tok.row, tok.col, tok.tok_type
-                            )
-                        );
-                    }
-                    let new_tok: Token;
-                    if let TokenType::IF(_) = tok.tok_type {
-                        self.if_stack += 1;
-                        self.if_end_stack = self.if_stack;
-                        new_tok = Token {
-                            col: tok.col,
-                            row: tok.row,
-                            tok_type: TokenType::IF(self.if_end_stack),
-                        };
-                    } else {
-                        self.else_stack = self.if_end_stack;
-                        if self.else_stack == 0 {
-                            return Err(format!(
-                                "{}:{}:{}: Invalid {:?}: Can't use {:?} without preceding IF",
-                                self.source_file, tok.col, tok.row, tok.tok_type, tok.tok_type
-                            ));
-                        }
+        fn parse_ident(&mut self, tok: &Token, name: &str) -> Result<Option<Node>, Diagnostic> {
+            let next = self.next_type();
+            if let Some(effect) = self.words.get(name).copied() {
+                if next == TokenType::SET {
+                    return Err(self.format_err(
+                        tok,
+                        format!("Invalid assignment: {} is a word, not a variable", name),
+                    ));
+                }
+                match effect {
+                    Some((req, out)) => self.stack_overflow(tok, req, out - req)?,
+                    None => self.stack_known = false,
+                }
+                return Ok(Some(Self::node(tok, NodeKind::Call(name.to_string()))));
+            }
 
-                        new_tok = Token {
-                            col: tok.col,
-                            row: tok.row,
-                            tok_type: TokenType::ELSE(self.else_stack),
-                        };
-                    }
-                    self.cur_block = EndBlock::Cond;
+            self.last_var = Some(name.to_string());
+            if self.vars.contains(name) {
+                if next == TokenType::SET {
+                    return Ok(None);
+                }
+                self.stack_overflow(tok, 0, 1)?;
+                return Ok(Some(Self::node(tok, NodeKind::Var(name.to_string()))));
+            }
+            if next != TokenType::SET {
+                return Err(self.format_err(tok, format!("Invalid: {} undefined", name)));
+            }
+            self.vars.insert(name.to_string());
+            Ok(None)
+        }
+
+        fn parse_set(&mut self, tok: &Token) -> Result<Node, Diagnostic> {
+            let var = match &self.last_var {
+                Some(v) => v.clone(),
+                None => return Err(self.format_err(tok, "No variable to assign to".to_string())),
+            };
+            self.stack_overflow(tok, 1, -1)?;
+            Ok(Self::node(tok, NodeKind::Assign(var)))
+        }
 
-                    Ok(new_tok)
+        // Records an error in the node that starts at start, then skips to where parsing can
+        // pick up again: a ; or end, or the first token on a later line. A stray ; or end is
+        // only skipped itself, so the rest of its line is still checked. The depth of the stack
+        // is unknown from there on, so the error isn't followed by underflows it caused
+        fn recover(&mut self, e: Diagnostic, start: usize) {
+            self.errors.push(e);
+            self.stack_known = false;
+            let row = self.tokens[self.pos].row;
+            if self.pos == start && self.tokens[self.pos].tok_type != TokenType::EOF {
+                let closer = is_closer(&self.tokens[self.pos].tok_type);
+                self.advance_token();
+                if closer {
+                    return;
+                }
+            }
+            loop {
+                let tok = &self.tokens[self.pos];
+                match tok.tok_type {
+                    TokenType::EOF => return,
+                    ref t if is_closer(t) => return,
+                    _ if tok.row > row => return,
+                    _ => self.advance_token(),
                 }
-                Err(e) => Err(e),
             }
         }
 
-        fn parse_while_block(
+        // Whether tok closes a block outside of the innermost one
+        fn closes_outer(&self, tok: &Token) -> bool {
+            let outer = &self.blocks[..self.blocks.len().saturating_sub(1)];
+            outer.iter().any(|(_, ends)| ends.contains(&tok.tok_type))
+        }
+
+        // Parses nodes until one of ends, and leaves pos at the token that ended the block
+        fn parse_block(
             &mut self,
-            tok: &Token,
-            req: i32,
-            change: i32,
-        ) -> Result<Token, String> {
-            let err = self.stack_overflow(tok, req, change);
-            match err {
-                Ok(i) => {
-                    if i < 1 {
-                        panic!(
-                            "{}",
-                            format!(
-                                "Invalid {:?}: Nothing on the stack to compare",
-                                tok.tok_type
-                            )
-                        );
+            open: &Token,
+            ends: &[TokenType],
+            message: String,
+        ) -> Result<Block, Diagnostic> {
+            if self.blocks.len() >= MAX_DEPTH {
+                return Err(self.format_err(open, "Blocks nested too deeply".to_string()));
+            }
+            self.blocks.push((open.clone(), ends.to_vec()));
+            let mut block = vec![];
+            let res = loop {
+                let tok = &self.tokens[self.pos];
+                if ends.contains(&tok.tok_type) {
+                    break Ok(block);
+                }
+                if tok.tok_type == TokenType::EOF {
+                    break Err(self.format_err(open, message));
+                }
+                // The token is left for the enclosing block it closes
+                if self.closes_outer(tok) {
+                    if let Some(e) = self.mismatched(tok) {
+                        break Err(e);
                     }
-                    let op = &self.tokens[self.pos - 1].tok_type;
-                    let new_tok: Token;
-                    self.loop_stack += 1;
-                    self.loop_end_stack = self.loop_stack;
-                    new_tok = Token {
-                        col: tok.col,
-                        row: tok.row,
-                        tok_type: TokenType::WHILE(Box::new(op.clone()), self.loop_end_stack),
-                    };
-                    self.cur_block = EndBlock::Loop;
-                    return Ok(new_tok);
                 }
-                Err(e) => Err(e),
+                let start = self.pos;
+                match self.parse_node(&mut block) {
+                    Ok(()) => self.advance_token(),
+                    Err(e) => self.recover(e, start),
+                }
+            };
+            self.blocks.pop();
+            res
+        }
+
+        // Error for a token that closes a block when the innermost open block expects another
+        fn mismatched(&self, tok: &Token) -> Option<Diagnostic> {
+            let (open, ends) = self.blocks.last()?;
+            let expected: Vec<String> = ends.iter().map(|t| format!("{:?}", t)).collect();
+            Some(self.format_err(
+                tok,
+                format!(
+                    "{:?} can't close the {:?} at {}:{}; expected {}",
+                    tok.tok_type,
+                    open.tok_type,
+                    open.row,
+                    open.col,
+                    expected.join(" or ")
+                ),
+            ))
+        }
+
+        // The depth after an if is only known when both branches leave the same depth
+        fn parse_if(&mut self, tok: &Token) -> Result<Node, Diagnostic> {
+            self.stack_overflow(tok, 1, -1)?;
+            let start = self.depth();
+            self.advance_token();
+            let then = self.parse_block(
+                tok,
+                &[TokenType::ELSE, TokenType::END],
+                "IF without closing END".to_string(),
+            )?;
+            let after_then = self.depth();
+            let mut els = None;
+            let mut after_else = start;
+            if self.tokens[self.pos].tok_type == TokenType::ELSE {
+                let else_tok = self.tokens[self.pos].clone();
+                self.set_depth(start);
+                self.advance_token();
+                els = Some(self.parse_block(
+                    &else_tok,
+                    &[TokenType::END],
+                    "ELSE without closing END".to_string(),
+                )?);
+                after_else = self.depth();
             }
+            self.set_depth(if after_then == after_else {
+                after_then
+            } else {
+                None
+            });
+            Ok(Self::node(tok, NodeKind::If { then, els }))
         }
 
-        fn parse_end(&mut self, tok: &Token) -> Result<Token, String> {
-            let new_tok: Token;
-            match self.cur_block {
-                EndBlock::Cond => {
-                    new_tok = Token {
-                        col: tok.col,
-                        row: tok.row,
-                        tok_type: TokenType::END(EndBlock::Cond, self.if_end_stack),
-                    };
-
-                    if self.if_end_stack == 0 {
-                        return Err(format!(
-                            "{}:{}:{}: Error parsing {:?}: {:?} without matching IF",
-                            self.source_file, tok.row, tok.col, tok.tok_type, tok.tok_type
-                        ));
-                    }
-                    self.if_end_stack -= 1;
-                    if self.else_stack > 0 {
-                        self.else_stack -= 1;
-                    }
-                    self.stack -= 2;
+        // The depth after a loop is only known when the condition pushes just the flag and the
+        // body leaves the depth it started with
+        fn parse_begin(&mut self, tok: &Token) -> Result<Node, Diagnostic> {
+            let start = self.depth();
+            self.advance_token();
+            let cond =
+                self.parse_block(tok, &[TokenType::WHILE], "BEGIN without WHILE".to_string())?;
+            let while_tok = self.tokens[self.pos].clone();
+            let flag = self.depth();
+            self.stack_overflow(&while_tok, 1, -1)?;
+            self.advance_token();
+            let body = self.parse_block(
+                &while_tok,
+                &[TokenType::REPEAT],
+                "WHILE without closing REPEAT".to_string(),
+            )?;
+            let balanced = flag == start.map(|d| d + 1) && self.depth() == start;
+            self.set_depth(if balanced { start } else { None });
+            Ok(Self::node(tok, NodeKind::While { cond, body }))
+        }
+
+        // a b < while ... end is the same loop as a b begin < while ... repeat, so the node
+        // before WHILE is evaluated again on every iteration
+        fn parse_while(&mut self, tok: &Token, block: &mut Block) -> Result<Node, Diagnostic> {
+            self.stack_overflow(tok, 1, -1)?;
+            let start = match block.pop() {
+                Some(n) => n,
+                None => {
+                    return Err(self.format_err(
+                        tok,
+                        "Invalid WHILE: Nothing on the stack to compare".to_string(),
+                    ))
                 }
-                EndBlock::Loop => {
-                    new_tok = Token {
-                        col: tok.col,
-                        row: tok.row,
-                        tok_type: TokenType::END(EndBlock::Loop, self.loop_end_stack),
-                    };
-                    if self.loop_end_stack == 0 {
-                        return Err(format!(
-                            "{}:{}:{}: Error parsing {:?}: {:?} without matching WHILE",
-                            self.source_file, tok.row, tok.col, tok.tok_type, tok.tok_type
-                        ));
-                    }
-                    self.loop_end_stack -= 1;
-                    self.stack -= 2;
+            };
+            let depth = self.depth();
+            self.advance_token();
+            let body = self.parse_block(
+                tok,
+                &[TokenType::END, TokenType::REPEAT],
+                "WHILE without closing END".to_string(),
+            )?;
+            let balanced = self.node_effect(&start) == Some(1) && self.depth() == depth;
+            self.set_depth(if balanced { depth } else { None });
+            Ok(Node {
+                row: start.row,
+                col: start.col,
+                span: start.span.clone(),
+                kind: NodeKind::While {
+                    cond: vec![start],
+                    body,
+                },
+            })
+        }
+
+        fn parse_node(&mut self, block: &mut Block) -> Result<(), Diagnostic> {
+            let tok = self.tokens[self.pos].clone();
+            if let Some(b) = Builtin::from_token(&tok.tok_type) {
+                let (mut req, out) = b.effect();
+                let change = out - req;
+                // n pick needs n + 1 values below n
+                if let (Builtin::Pick, Some(NodeKind::Literal(Literal::Int(n)))) =
+                    (b, block.last().map(|n| &n.kind))
+                {
+                    req = n.saturating_add(2).clamp(1, i32::MAX as i64) as i32;
+                }
+                self.stack_overflow(&tok, req, change)?;
+                block.push(Self::node(&tok, NodeKind::Builtin(b)));
+                return Ok(());
+            }
+            if let TokenType::ELSE | TokenType::END | TokenType::REPEAT | TokenType::SEMICOLON =
+                tok.tok_type
+            {
+                if let Some(e) = self.mismatched(&tok) {
+                    return Err(e);
+                }
+            }
+            let node = match &tok.tok_type {
+                TokenType::INT(i) => {
+                    self.stack_overflow(&tok, 0, 1)?;
+                    Self::node(&tok, NodeKind::Literal(Literal::Int(*i)))
+                }
+                TokenType::FLOAT(f) => {
+                    self.stack_overflow(&tok, 0, 1)?;
+                    Self::node(&tok, NodeKind::Literal(Literal::Float(*f)))
                 }
+                TokenType::STR(s) => {
+                    self.stack_overflow(&tok, 0, 1)?;
+                    Self::node(&tok, NodeKind::Literal(Literal::Str(s.clone())))
+                }
+                TokenType::IDENT(s) => match self.parse_ident(&tok, s)? {
+                    Some(n) => n,
+                    None => return Ok(()),
+                },
+                TokenType::SET => self.parse_set(&tok)?,
+                TokenType::IF => self.parse_if(&tok)?,
+                TokenType::BEGIN => self.parse_begin(&tok)?,
+                TokenType::WHILE => self.parse_while(&tok, block)?,
+                TokenType::ELSE => {
+                    return Err(
+                        self.format_err(&tok, "Can't use ELSE without preceding IF".to_string())
+                    )
+                }
+                TokenType::END => {
+                    return Err(self.format_err(&tok, "END without matching block".to_string()))
+                }
+                TokenType::REPEAT => {
+                    return Err(self.format_err(&tok, "REPEAT without matching BEGIN".to_string()))
+                }
+                TokenType::COLON => {
+                    return Err(
+                        self.format_err(&tok, "Can't define a word inside a word".to_string())
+                    )
+                }
+                TokenType::SEMICOLON => {
+                    return Err(self.format_err(&tok, "; without matching :".to_string()))
+                }
+                TokenType::RPAREN => {
+                    return Err(self.format_err(
+                        &tok,
+                        "Hanging ); ) should only be used to close a matching (".to_string(),
+                    ))
+                }
+                _ => {
+                    return Err(self.format_err(&tok, format!("parser: Unhandled token: {:?}", tok)))
+                }
+            };
+            block.push(node);
+            Ok(())
+        }
+
+        // ( a b -- c ) names the values a word takes and the values it leaves
+        fn parse_signature(&self, tok: &Token, text: &str) -> Result<Signature, Diagnostic> {
+            let names: Vec<String> = text.split_whitespace().map(|n| n.to_string()).collect();
+            let sep: Vec<usize> = (0..names.len()).filter(|i| names[*i] == "--").collect();
+            if sep.len() != 1 {
+                return Err(self.format_err(
+                    tok,
+                    format!(
+                        "Invalid signature ({}): expected ( inputs -- outputs )",
+                        text
+                    ),
+                ));
             }
-            Ok(new_tok)
+            Ok(Signature {
+                inputs: names[..sep[0]].to_vec(),
+                outputs: names[sep[0] + 1..].to_vec(),
+            })
         }
 
-        fn parse_matched_token(&mut self, tok: &Token, tok_type: TokenType) -> Result<u32, String> {
-            let pos = self.pos;
-            let peek = self.peek;
-            while self.tokens[self.pos].tok_type != tok_type {
-                self.advance_token();
-                if self.tokens[self.pos].tok_type == TokenType::EOF {
-                    return Err(format!(
-                        "{}:{}:{}: Error parsing {:?}: {:?} without closing {:?}",
-                        self.source_file, tok.row, tok.col, tok.tok_type, tok.tok_type, tok_type
-                    ));
+        // Compares the depth left by the body of a word with its signature. Without a
+        // signature, the effect inferred from the body is used at call sites instead
+        fn check_effect(
+            &mut self,
+            name: &str,
+            signature: &Option<Signature>,
+            end: &Token,
+        ) -> Result<(), Diagnostic> {
+            let depth = match self.depth() {
+                Some(d) => d,
+                None => return Ok(()),
+            };
+            match signature.as_ref().and_then(|s| s.effect().map(|e| (s, e))) {
+                Some((sig, (_, out))) if depth != out => Err(self.format_err(
+                    end,
+                    format!(
+                        "Invalid word {}: {} leaves {}, but its body leaves {}",
+                        name, sig, out, depth
+                    ),
+                )),
+                Some(_) => Ok(()),
+                None => {
+                    self.words
+                        .insert(name.to_string(), Some((-self.low, depth - self.low)));
+                    Ok(())
                 }
             }
-            self.pos = pos;
-            self.peek = peek;
-            Ok(0)
         }
 
-        fn parse_word(&mut self, tok: &Token) -> Result<u32, String> {
-            self.parse_matched_token(tok, TokenType::SEMICOLON)
+        // Returns None, after skipping to the end of the definition, if the name is invalid
+        fn parse_word(&mut self, colon: &Token) -> Result<Option<WordDef>, Diagnostic> {
+            self.advance_token();
+            let name = match &self.tokens[self.pos].tok_type {
+                TokenType::IDENT(s) => s.clone(),
+                _ => {
+                    let e = self.format_err(
+                        &self.tokens[self.pos],
+                        "New word error: Invalid name".to_string(),
+                    );
+                    self.errors.push(e);
+                    while !matches!(
+                        self.tokens[self.pos].tok_type,
+                        TokenType::SEMICOLON | TokenType::EOF
+                    ) {
+                        self.advance_token();
+                    }
+                    return Ok(None);
+                }
+            };
+            let mut signature = None;
+            if let TokenType::SIGNATURE(text) = self.next_type() {
+                self.advance_token();
+                match self.parse_signature(&self.tokens[self.pos], &text) {
+                    Ok(sig) => signature = Some(sig),
+                    Err(e) => self.errors.push(e),
+                }
+            }
+            let effect = signature.as_ref().and_then(|s| s.effect());
+            // Registered before the body is parsed so the word can call itself
+            self.words.insert(name.clone(), effect);
+
+            // The body starts with the values the signature says it takes; without one, it
+            // starts empty and takes whatever it needs from the caller
+            let vars = std::mem::take(&mut self.vars);
+            let last_var = self.last_var.take();
+            let (depth, inferring, low) = (self.depth(), self.inferring, self.low);
+            self.set_depth(Some(effect.map_or(0, |(req, _)| req)));
+            self.inferring = effect.is_none();
+            self.low = 0;
+            self.advance_token();
+            let body = self.parse_block(
+                colon,
+                &[TokenType::SEMICOLON],
+                format!("Word {} without closing ;", name),
+            );
+            if body.is_ok() {
+                let end = self.tokens[self.pos].clone();
+                if let Err(e) = self.check_effect(&name, &signature, &end) {
+                    self.errors.push(e);
+                }
+            }
+            self.set_depth(depth);
+            self.inferring = inferring;
+            self.low = low;
+            self.vars = vars;
+            self.last_var = last_var;
+
+            Ok(Some(WordDef {
+                name,
+                signature,
+                body: body?,
+                row: colon.row,
+                col: colon.col,
+            }))
         }
 
-        pub fn parse(&mut self) -> Result<u32, String> {
+        // Parses the whole program, going on after errors so all of them can be reported
+        pub fn parse(&mut self) -> Result<Program, Vec<Diagnostic>> {
+            let mut program = Program::default();
             while self.tokens[self.pos].tok_type != TokenType::EOF {
-                let tok = &self.tokens[self.pos].clone();
-                let err = match &tok.tok_type {
-                    TokenType::PLUS => self.stack_overflow(tok, 2, -1),
-                    TokenType::MINUS => self.stack_overflow(tok, 2, -1),
-                    TokenType::ASTERISK => self.stack_overflow(tok, 2, -1),
-                    TokenType::SLASH => self.stack_overflow(tok, 2, -1),
-                    TokenType::EQUAL => self.stack_overflow(tok, 2, 1),
-                    TokenType::NEQUAL => self.stack_overflow(tok, 2, 1),
-                    TokenType::LTE => self.stack_overflow(tok, 2, 1),
-                    TokenType::LT => self.stack_overflow(tok, 2, 1),
-                    TokenType::GTE => self.stack_overflow(tok, 2, 1),
-                    TokenType::GT => self.stack_overflow(tok, 2, 1),
-                    TokenType::PERIOD => self.stack_overflow(tok, 1, -1),
-                    TokenType::COMMA => self.stack_overflow(tok, 1, 0),
-                    // TokenType::DUP => self.stack_overflow(tok, 1, 1),
-                    // TokenType::SWAP => self.stack_overflow(tok, 2, 0),
-                    // TokenType::NIP => self.stack_overflow(tok, 2, -1),
-                    // TokenType::ROT => self.stack_overflow(tok, 3, 0),
-                    TokenType::SET => self.parse_set(),
-                    TokenType::DBG => Ok(self.stack),
-                    TokenType::CHAR => Ok(self.stack),
-                    TokenType::INT(_) => Ok(self.stack + 1),
-                    TokenType::STR(_) => Ok(self.stack + 1),
-                    TokenType::IF(_) => match self.parse_if_block(tok, 1, -1) {
-                        Ok(t) => {
-                            self.tokens[self.pos] = t;
-                            Ok(self.stack)
-                        }
-                        Err(e) => Err(e),
-                    },
-                    TokenType::ELSE(_) => match self.parse_if_block(tok, 1, 0) {
-                        Ok(t) => {
-                            self.tokens[self.pos] = t;
-                            Ok(self.stack)
-                        }
-                        Err(e) => Err(e),
-                    },
-                    TokenType::END(_, _) => match self.parse_end(tok) {
-                        Ok(t) => {
-                            self.tokens[self.pos] = t;
-                            Ok(self.stack)
-                        }
-                        Err(e) => Err(e),
-                    },
-                    TokenType::WHILE(_, _) => match self.parse_while_block(tok, 1, 0) {
-                        Ok(t) => {
-                            self.tokens[self.pos] = t;
-                            Ok(self.stack)
-                        }
-                        Err(e) => Err(e),
-                    },
-                    TokenType::IDENT(s) => match self.parse_ident(&s) {
-                        Ok(i) => Ok(i),
-                        Err(e) => Err(e),
-                    },
-                    TokenType::COLON => {
-                        self.parse_inside = false;
-                        match self.parse_word(tok) {
-                            Ok(_) => Ok(0),
-                            Err(e) => Err(e),
-                        }
-                    }
-                    TokenType::SEMICOLON => {
-                        self.parse_inside = true;
-                        Ok(0)
-                    }
-                    TokenType::LPAREN => match self.parse_matched_token(tok, TokenType::RPAREN) {
-                        Ok(_) => Ok(0),
-                        Err(e) => Err(e),
-                    },
-                    TokenType::RPAREN => Err(format!(
-                        "{}:{}:{}: Hanging ); ) should only be used to close a matching (",
-                        self.source_file, tok.row, tok.col
-                    )),
-                    TokenType::EM => Ok(0),
-
-                    TokenType::EOF => {
-                        if self.if_end_stack > 0 {
-                            Err(format!(
-                                "{}:{}:{}: Unclosed IF",
-                                self.source_file, tok.row, tok.col
-                            ))
-                        } else {
-                            break;
-                        }
-                    }
-                    _ => return Err(format!("parser: Unhandled token: {:?}", tok)),
+                let tok = self.tokens[self.pos].clone();
+                let start = self.pos;
+                let res = if tok.tok_type == TokenType::COLON {
+                    self.parse_word(&tok).map(|word| program.words.extend(word))
+                } else {
+                    self.parse_node(&mut program.main)
                 };
-                match err {
-                    Ok(i) => {
-                        self.stack = i;
-                        self.advance_token();
+                match res {
+                    Ok(()) => self.advance_token(),
+                    Err(e) => {
+                        self.recover(e, start);
+                        // Nothing is open here for the token recover stopped at to close; it
+                        // ends the construct that failed, like the ; of a word with a bad name
+                        if !is_closer(&tok.tok_type) && is_closer(&self.tokens[self.pos].tok_type) {
+                            self.advance_token();
+                        }
                     }
-                    Err(e) => return Err(e),
                 }
             }
-            Ok(0)
+            if !self.errors.is_empty() {
+                return Err(std::mem::take(&mut self.errors));
+            }
+            Ok(program)
         }
     }
 }
@@ -1,591 +1,804 @@
 pub mod qbe_backend {
     use std::{
         collections::{HashMap, HashSet},
-        fs::File,
-        io::{self, Write},
+        fs,
+        path::{Path, PathBuf},
         process::Command,
     };
 
-    use crate::lexer::lexer::{EndBlock, Token, TokenType};
+    use crate::{
+        ast::ast::{Block, Builtin, Literal, Node, NodeKind, Program, WordDef},
+        diagnostic::diagnostic::{self, Diagnostic},
+    };
+
+    // Number of cells in the runtime data stack
+    const STACK_CELLS: usize = 8192;
+    // Each cell holds an 8 byte value followed by an 8 byte type tag
+    const CELL_SIZE: usize = 16;
+    const TAG_INT: u8 = 0;
+    const TAG_STR: u8 = 1;
+    const TAG_FLOAT: u8 = 2;
+
+    // Where the generated files go and the tools that turn them into a binary
+    #[derive(Debug, Clone)]
+    pub struct Options {
+        // Directory for the .ssa, the .s and the binary; created if it's missing
+        pub out_dir: PathBuf,
+        // Name of the binary; the .ssa and .s files are named after it
+        pub bin_name: String,
+        pub qbe: String,
+        // C compiler used to assemble and link the output of qbe
+        pub cc: String,
+        // Extra flags passed to cc when linking, like -static
+        pub link_flags: Vec<String>,
+        // Runs the binary once it's linked
+        pub run: bool,
+    }
 
-    enum Peek {
-        Stack,
-        Word(String, usize),
+    impl Default for Options {
+        fn default() -> Options {
+            Options {
+                out_dir: PathBuf::from("./out"),
+                bin_name: "rorth".to_string(),
+                qbe: "qbe".to_string(),
+                cc: "gcc".to_string(),
+                link_flags: vec![],
+                run: false,
+            }
+        }
     }
 
-    type StackPointer = i32;
     pub struct Compiler {
-        // Name of the source file
-        source: String,
-        tokens: Vec<Token>,
-        output_file: File,
-        // Number representing the size of the stack
-        stack: StackPointer,
-        pos: usize,
-        peek: usize,
-        pub strings: HashMap<String, String>,
-        // HashMap to keep track of strings on the stack
-        pub string_stack: HashMap<StackPointer, String>,
-        // HashMap in the form "word name": [list of operations associated with the word]
-        words: HashMap<String, Vec<Token>>,
-        var_stack: Vec<String>,
-        // Set containing the names of all variables declared in the program
-        vars: HashSet<String>,
-        // Name of the word the compiler is currently looking at; prepended to variable names in the
-        // generated qbe code to allow variable scoping
-        // : word 1 x := ; => x becomes %s_word_x
-        // 1 x := => x becomes %s_x
-        cur_word: String,
-        if_stack: i32,
-        else_stack: i32,
-        if_end_stack: i32,
-        loop_stack: i32,
-        loop_end_stack: i32,
+        // Body of the function currently being generated; each word is generated as its own
+        // qbe function, so variables are local to the word
+        // : word 1 x := ; => x becomes %s_x in $w_word
+        // 1 x := => x becomes %s_x in $main
+        body: String,
+        // Finished functions, one for each word
+        functions: Vec<String>,
+        // Counter used to name temporaries and labels
+        tmp: usize,
+        // String literals in the order they first appear; literal i is stored in $str_i
+        pub strings: Vec<String>,
+        // HashMap in the form "contents": index into strings, so equal literals share data
+        string_ids: HashMap<String, usize>,
+        // Names of the program's words
+        words: HashSet<String>,
+    }
+
+    // Word and variable names can hold any character, but qbe names can't; letters and digits
+    // are kept and everything else, _ included, becomes _ and its hex code, so `2dup` stays
+    // 2dup and `+!` becomes _2b__21_
+    fn mangle(name: &str) -> String {
+        let mut out = String::new();
+        for c in name.chars() {
+            if c.is_ascii_alphanumeric() {
+                out.push(c);
+            } else {
+                out.push_str(&format!("_{:x}_", c as u32));
+            }
+        }
+        out
     }
 
-    pub fn new(source: String, tokens: Vec<Token>) -> Compiler {
-        let file = File::create("./out/rorth.ssa").unwrap();
+    pub fn new() -> Compiler {
         Compiler {
-            source,
-            tokens,
-            output_file: file,
-            stack: 0,
-            pos: 0,
-            peek: 1,
-            strings: HashMap::new(),
-            string_stack: HashMap::new(),
-            words: HashMap::new(),
-            var_stack: vec![],
-            vars: HashSet::new(),
-            cur_word: "main".to_string(),
-            if_stack: 0,
-            else_stack: 0,
-            if_end_stack: 0,
-            loop_stack: 0,
-            loop_end_stack: 0,
+            body: String::new(),
+            functions: vec![],
+            tmp: 0,
+            strings: vec![],
+            string_ids: HashMap::new(),
+            words: HashSet::new(),
         }
     }
 
     impl Compiler {
-        fn format_err(&self, tok: &Token, message: String) -> Result<i32, String> {
-            Err(format!(
-                "{}:{}:{}: {} {:?}",
-                self.source, tok.row, tok.col, message, tok.tok_type
-            ))
+        fn format_err(&self, node: &Node, message: String) -> Diagnostic {
+            diagnostic::error(&node.span, message)
         }
 
-        fn advance_token(&mut self) {
-            self.pos += 1;
-            self.peek += 1;
+        fn emit(&mut self, s: &str) {
+            self.body.push_str(s);
         }
 
-        fn write_op(&mut self, op: &str) -> Result<i32, String> {
-            if let TokenType::STR(_) = self.tokens[self.pos - 1].tok_type {
-                return self.format_err(
-                    &self.tokens[self.pos - 1],
-                    format!("Invalid types for {}; can't use {} with strings", op, op),
-                );
-            }
-            if let TokenType::STR(_) = self.tokens[self.pos - 2].tok_type {
-                return self.format_err(
-                    &self.tokens[self.pos - 2],
-                    format!("Invalid types for {}; can't use {} with strings", op, op),
-                );
-            }
+        fn new_tmp(&mut self) -> String {
+            self.tmp += 1;
+            format!("%t{}", self.tmp)
+        }
+
+        // Jumps to the function's @underflow block unless there are at least `depth` values on
+        // the runtime stack
+        fn check_depth(&mut self, depth: usize) {
+            let addr = self.new_tmp();
+            let c = self.new_tmp();
+            self.tmp += 1;
             let s = format!(
-                "\t%s_main_{} =d {} %s_main_{}, %s_main_{}\n",
-                self.stack - 1,
-                op,
-                self.stack - 1,
-                self.stack
+                "\t{} =l sub %sp, {}\n\t{} =w cultl {}, $stack\n\tjnz {}, @underflow, @ok_{}\n@ok_{}\n",
+                addr,
+                depth * CELL_SIZE,
+                c,
+                addr,
+                c,
+                self.tmp,
+                self.tmp
             );
-            self.output_file.write(s.as_bytes()).unwrap();
-            Ok(self.stack - 1)
+            self.emit(&s);
         }
 
-        fn push_op(&mut self, value: &Token) -> i32 {
-            let stack = self.stack + 1;
-            let s: String;
-            match &value.tok_type {
-                TokenType::INT(i) => s = format!("\t%s_main_{} =d add 0, d_{}\n", stack, i),
-                TokenType::IDENT(var) => s = format!("\t%s_main_{} =d add 0, %s_{}\n", stack, var),
-                _ => panic!("Invalid push target: {:?}", value),
-            }
-            self.output_file.write(s.as_bytes()).unwrap();
-            stack
-        }
-
-        fn comp_op(&mut self, op: &str) -> (i32, String) {
-            let stack = self.stack + 1;
-            let op = match op {
-                "=" => "eq",
-                "!=" => "ne",
-                "<=" => "le",
-                "<" => "lt",
-                ">=" => "ge",
-                ">" => "gt",
-                _ => panic!("Unknown op: {}", op),
-            };
+        fn check_space(&mut self) {
+            let end = self.new_tmp();
+            let c = self.new_tmp();
+            self.tmp += 1;
             let s = format!(
-                "\t%b =w c{}d %s_main_{}, %s_main_{}\n\t%s_main_{} =d swtof %b\n",
-                op,
-                stack - 2,
-                stack - 1,
-                stack,
+                "\t{} =l add $stack, {}\n\t{} =w cugel %sp, {}\n\tjnz {}, @overflow, @ok_{}\n@ok_{}\n",
+                end,
+                STACK_CELLS * CELL_SIZE,
+                c,
+                end,
+                c,
+                self.tmp,
+                self.tmp
             );
-            self.output_file.write(s.as_bytes()).unwrap();
-            (stack, s)
+            self.emit(&s);
         }
 
-        fn set_op(&mut self, var_name: String, tok: Token) -> Result<i32, String> {
-            if self.stack < 1 {
-                return self.format_err(
-                    &tok,
-                    format!("Invalid {:?}: Not enough values on the stack", tok),
-                );
-            }
+        // The runtime stack grows upwards from $stack; %sp points at the next free cell
+        fn push_value(&mut self, ty: &str, value: &str, tag: &str) {
+            self.check_space();
+            let t = self.new_tmp();
+            let s = format!(
+                "\tstore{} {}, %sp\n\t{} =l add %sp, 8\n\tstorel {}, {}\n\t%sp =l add %sp, {}\n",
+                ty, value, t, tag, t, CELL_SIZE
+            );
+            self.emit(&s);
+        }
 
-            let s = if self.string_stack.contains_key(&self.stack) {
-                format!(
-                    "\t%{} =l add 0, ${}\n",
-                    var_name, self.string_stack[&self.stack]
-                )
-            } else {
-                format!("\t%{} =d add 0, %s_main_{}\n", var_name, self.stack)
-            };
-            self.output_file.write(s.as_bytes()).unwrap();
-            Ok(self.stack - 1)
+        // Pops the top cell and returns a temporary holding its address
+        fn pop_cell(&mut self) -> String {
+            self.check_depth(1);
+            let t = self.new_tmp();
+            let s = format!("\t%sp =l sub %sp, {}\n\t{} =l copy %sp\n", CELL_SIZE, t);
+            self.emit(&s);
+            t
         }
 
-        fn print_op(&mut self, tok: &Token) -> Result<i32, String> {
-            if self.stack == 0 {
-                return self.format_err(&tok, "Nothing on the stack to print".to_string());
-            }
-            let s: String = match &tok.tok_type {
-                TokenType::PERIOD => {
-                    if self.string_stack.contains_key(&self.stack) {
-                     let s = format!("\tcall $printf(l $fmt_str, ..., l ${})\n", self.string_stack.get(&self.stack).unwrap());
-                        self.string_stack.remove(&self.stack);
-                        s
-                    } else {
-                        format!("\tcall $printf(l $fmt_dec, ..., d %s_main_{})\n",self.stack)
-                        }
-                    },
-                TokenType::CHAR => format!(
-                    "\t%s_main_{}_w =w dtosi %s_main_{}\n\tcall $printf(l $fmt_char, ..., w %s_main_{}_w)\n",
-                    self.stack, self.stack, self.stack
-                ),
-                _ => {
-                    return self.format_err(tok, format!("Invalid target: {:?} not printable", tok))
-                }
+        // Returns a temporary holding the address of the cell `depth` cells below the top of
+        // the stack
+        fn peek_cell(&mut self, depth: usize) -> String {
+            self.check_depth(depth);
+            let t = self.new_tmp();
+            let s = format!("\t{} =l sub %sp, {}\n", t, depth * CELL_SIZE);
+            self.emit(&s);
+            t
+        }
+
+        fn load(&mut self, ty: &str, addr: &str) -> String {
+            let t = self.new_tmp();
+            let s = format!("\t{} ={} load{} {}\n", t, ty, ty, addr);
+            self.emit(&s);
+            t
+        }
+
+        fn load_tag(&mut self, cell: &str) -> String {
+            let addr = self.new_tmp();
+            let s = format!("\t{} =l add {}, 8\n", addr, cell);
+            self.emit(&s);
+            self.load("l", &addr)
+        }
+
+        // Writes a value and its tag into a cell that is already on the stack
+        fn store_cell(&mut self, ty: &str, cell: &str, value: &str, tag: u8) {
+            let addr = self.new_tmp();
+            let s = format!(
+                "\tstore{} {}, {}\n\t{} =l add {}, 8\n\tstorel {}, {}\n",
+                ty, value, cell, addr, cell, tag, addr
+            );
+            self.emit(&s);
+        }
+
+        // Jumps to @float_N if the cell holds a float and to @int_N otherwise, returning N
+        fn dispatch(&mut self, cell: &str) -> usize {
+            let tag = self.load_tag(cell);
+            let f = self.new_tmp();
+            self.tmp += 1;
+            let id = self.tmp;
+            let s = format!(
+                "\t{} =w ceql {}, {}\n\tjnz {}, @float_{}, @int_{}\n@int_{}\n",
+                f, tag, TAG_FLOAT, f, id, id, id
+            );
+            self.emit(&s);
+            id
+        }
+
+        fn pop_value(&mut self, ty: &str) -> String {
+            let cell = self.pop_cell();
+            self.load(ty, &cell)
+        }
+
+        // Integer arithmetic; / truncates towards 0 and mod takes the sign of the dividend
+        fn int_op(&mut self, op: &Builtin, a_cell: &str, b_cell: &str) {
+            let a = self.load("l", a_cell);
+            let b = self.load("l", b_cell);
+            let instr = match op {
+                Builtin::Add => "add",
+                Builtin::Sub => "sub",
+                Builtin::Mul => "mul",
+                Builtin::Div => "div",
+                _ => "rem",
             };
-            self.output_file.write(s.as_bytes()).unwrap();
-            Ok(self.stack - 1)
+            if *op != Builtin::Div && *op != Builtin::Mod {
+                let t = self.new_tmp();
+                self.emit(&format!("\t{} =l {} {}, {}\n", t, instr, a, b));
+                self.store_cell("l", a_cell, &t, TAG_INT);
+                return;
+            }
+            let z = self.new_tmp();
+            let m = self.new_tmp();
+            self.tmp += 1;
+            let id = self.tmp;
+            let s = format!(
+                "\t{} =w ceql {}, 0\n\tjnz {}, @divzero, @ok_{}\n@ok_{}\n\t{} =w ceql {}, -1\n\tjnz {}, @minus_one_{}, @divide_{}\n@minus_one_{}\n",
+                z, b, z, id, id, m, b, m, id, id, id
+            );
+            self.emit(&s);
+            // Dividing i64::MIN by -1 traps, so x / -1 is worked out as 0 - x, which wraps to
+            // i64::MIN like the interpreter, and x mod -1 is always 0
+            let t = self.new_tmp();
+            match op {
+                Builtin::Div => self.emit(&format!("\t{} =l sub 0, {}\n", t, a)),
+                _ => self.emit(&format!("\t{} =l copy 0\n", t)),
+            }
+            self.store_cell("l", a_cell, &t, TAG_INT);
+            self.emit(&format!("\tjmp @divided_{}\n@divide_{}\n", id, id));
+            let t = self.new_tmp();
+            self.emit(&format!("\t{} =l {} {}, {}\n", t, instr, a, b));
+            self.store_cell("l", a_cell, &t, TAG_INT);
+            self.emit(&format!("@divided_{}\n", id));
         }
 
-        fn dbg_op(&mut self) {
-            println!("Stack size: {}", self.stack);
-            println!("Strings: {:?}", self.string_stack);
-            let s = format!("\tcall $puts(w 0)\n");
-            self.output_file.write(s.as_bytes()).unwrap();
-            let s = format!("\tcall $printf(l $fmt_str, ..., l $dbg)\n",);
-            self.output_file.write(s.as_bytes()).unwrap();
-            for i in 1..=self.stack {
-                let s = if self.string_stack.contains_key(&i) {
-                    format!(
-                        "\tcall $printf(l $fmt_str, ..., l ${})\n",
-                        self.string_stack[&i]
-                    )
-                } else {
-                    format!("\tcall $printf(l $fmt_dec, ..., d %s_main_{})\n", i)
+        // The same words work on ints and floats, so the tag picks the instruction at runtime;
+        // typechecker makes sure both operands have the same type. The result replaces the
+        // deeper operand
+        fn math_op(&mut self, op: &Builtin) {
+            let b = self.pop_cell();
+            let a = self.pop_cell();
+            if *op == Builtin::Mod {
+                self.int_op(op, &a, &b);
+            } else {
+                let id = self.dispatch(&b);
+                self.int_op(op, &a, &b);
+                self.emit(&format!("\tjmp @math_end_{}\n@float_{}\n", id, id));
+                let x = self.load("d", &a);
+                let y = self.load("d", &b);
+                let t = self.new_tmp();
+                let instr = match op {
+                    Builtin::Add => "add",
+                    Builtin::Sub => "sub",
+                    Builtin::Mul => "mul",
+                    _ => "div",
                 };
-                self.output_file.write(s.as_bytes()).unwrap();
+                self.emit(&format!("\t{} =d {} {}, {}\n", t, instr, x, y));
+                self.store_cell("d", &a, &t, TAG_FLOAT);
+                self.emit(&format!("@math_end_{}\n", id));
             }
-            let s = format!("\tcall $puts(w 0)\n");
-            self.output_file.write(s.as_bytes()).unwrap();
+            self.emit(&format!("\t%sp =l add {}, {}\n", a, CELL_SIZE));
         }
 
-        fn new_word_op(&mut self) -> Result<i32, String> {
-            self.advance_token();
-            let cur_token = self.tokens[self.pos].clone();
-            let word_name = match &cur_token.tok_type {
-                TokenType::IDENT(s) => s,
-                _ => {
-                    return self.format_err(&cur_token, "New word error: Invalid name".to_string())
-                }
-            };
-            self.words.insert(word_name.clone(), vec![]);
-            self.cur_word = word_name.clone();
-
-            if self.peek(Peek::Stack).tok_type == TokenType::LPAREN {
-                let mut cur_token = self.tokens[self.pos].clone();
-                while cur_token.tok_type != TokenType::RPAREN {
-                    self.advance_token();
-                    cur_token = self.tokens[self.pos].clone();
-                }
-                self.advance_token();
+        fn conv_op(&mut self, op: &Builtin) {
+            let t = self.new_tmp();
+            if *op == Builtin::IntToFloat {
+                let v = self.pop_value("l");
+                self.emit(&format!("\t{} =d sltof {}\n", t, v));
+                self.push_value("d", &t, &TAG_FLOAT.to_string());
             } else {
-                self.advance_token();
+                let v = self.pop_value("d");
+                self.emit(&format!("\t{} =l dtosi {}\n", t, v));
+                self.push_value("l", &t, &TAG_INT.to_string());
             }
+        }
 
-            let mut cur_token = self.tokens[self.pos].clone();
-            while cur_token.tok_type != TokenType::SEMICOLON {
-                if let TokenType::IDENT(s) = &cur_token.tok_type {
-                    if !self.words.contains_key(s) {
-                        cur_token.tok_type = TokenType::IDENT(format!("{}_{}", self.cur_word, s));
-                    }
-                }
-                self.words.get_mut(word_name).unwrap().push(cur_token);
-                self.advance_token();
-                cur_token = self.tokens[self.pos].clone();
-            }
-            self.words.get_mut(word_name).unwrap().push(Token {
-                row: 0,
-                col: 0,
-                tok_type: TokenType::EOF,
-            });
-            Ok(0)
-        }
-
-        fn peek(&self, stack: Peek) -> &Token {
-            match stack {
-                Peek::Stack => &self.tokens[self.peek],
-                Peek::Word(s, i) => {
-                    let word = self.words.get(&s).unwrap();
-                    return &word[i];
-                }
+        // Suffixes of the int and float comparison instructions
+        fn comp_instr(op: &Builtin) -> (&'static str, &'static str) {
+            match op {
+                Builtin::Eq => ("eq", "eq"),
+                Builtin::Ne => ("ne", "ne"),
+                Builtin::Le => ("sle", "le"),
+                Builtin::Lt => ("slt", "lt"),
+                Builtin::Ge => ("sge", "ge"),
+                _ => ("sgt", "gt"),
             }
         }
 
-        fn handle_while(
-            &mut self,
-            op: Box<TokenType>,
-            pos: i32,
-            tok: Token,
-        ) -> Result<i32, String> {
-            self.stack -= 1;
+        // Compares the top two values without popping them and pushes the result
+        fn comp_op(&mut self, op: &Builtin) {
+            let (int, float) = Self::comp_instr(op);
+            let a_cell = self.peek_cell(2);
+            let b_cell = self.peek_cell(1);
+            let id = self.dispatch(&b_cell);
+            let a = self.load("l", &a_cell);
+            let b = self.load("l", &b_cell);
+            let wi = self.new_tmp();
             let s = format!(
-                "\t%c_{}_{} =d add 0, %s_main_{}\n",
-                pos,
-                pos,
-                self.stack - 1
+                "\t{} =w c{}l {}, {}\n\tjmp @comp_end_{}\n@float_{}\n",
+                wi, int, a, b, id, id
             );
-            self.output_file.write(s.as_bytes()).unwrap();
+            self.emit(&s);
+            let x = self.load("d", &a_cell);
+            let y = self.load("d", &b_cell);
+            let wf = self.new_tmp();
+            let w = self.new_tmp();
+            let t = self.new_tmp();
             let s = format!(
-                "\t%c_{}_{} =d add 0, %s_main_{}\n",
-                pos,
-                pos + 1,
-                self.stack
+                "\t{} =w c{}d {}, {}\n@comp_end_{}\n\t{} =w phi @int_{} {}, @float_{} {}\n\t{} =l extsw {}\n",
+                wf, float, x, y, id, w, id, wi, id, wf, t, w
             );
-            self.output_file.write(s.as_bytes()).unwrap();
-            let comp = match *op.to_owned() {
-                TokenType::EQUAL => "eq",
-                TokenType::NEQUAL => "ne",
-                TokenType::LTE => "le",
-                TokenType::LT => "lt",
-                TokenType::GTE => "ge",
-                TokenType::GT => "gt",
+            self.emit(&s);
+            self.push_value("l", &t, &TAG_INT.to_string());
+        }
+
+        // Pops the operands, treating any number other than 0 as true, and pushes 1 or 0
+        fn logic_op(&mut self, op: &Builtin) {
+            let b = self.pop_value("l");
+            let bw = self.new_tmp();
+            self.emit(&format!("\t{} =w cnel {}, 0\n", bw, b));
+            let w = self.new_tmp();
+            let s = match op {
+                Builtin::Not => format!("\t{} =w ceqw {}, 0\n", w, bw),
                 _ => {
-                    return self.format_err(
-                        &tok,
-                        format!("compiler: Error handling comparison: {:?}", tok),
+                    let a = self.pop_value("l");
+                    let aw = self.new_tmp();
+                    let instr = if *op == Builtin::And { "and" } else { "or" };
+                    format!(
+                        "\t{} =w cnel {}, 0\n\t{} =w {} {}, {}\n",
+                        aw, a, w, instr, aw, bw
                     )
                 }
             };
+            let t = self.new_tmp();
+            self.emit(&format!("{}\t{} =l extsw {}\n", s, t, w));
+            self.push_value("l", &t, &TAG_INT.to_string());
+        }
+
+        // Loads the cells the op needs, drops them and pushes them back in the op's order; the
+        // raw bits of each value are copied, whatever its type
+        fn stack_op(&mut self, op: &Builtin) {
+            let (req, _) = op.effect();
+            let order: &[usize] = match op {
+                Builtin::Dup => &[0, 0],
+                Builtin::Swap => &[1, 0],
+                Builtin::Nip => &[1],
+                Builtin::Rot => &[1, 2, 0],
+                Builtin::Over => &[0, 1, 0],
+                Builtin::TwoDup => &[0, 1, 0, 1],
+                Builtin::Tuck => &[1, 0, 1],
+                _ => &[],
+            };
+            let mut cells = vec![];
+            for i in 0..req as usize {
+                let cell = self.peek_cell(req as usize - i);
+                let v = self.load("l", &cell);
+                let tag = self.load_tag(&cell);
+                cells.push((v, tag));
+            }
+            let s = format!("\t%sp =l sub %sp, {}\n", req as usize * CELL_SIZE);
+            self.emit(&s);
+            for i in order {
+                let (v, tag) = cells[*i].clone();
+                self.push_value("l", &v, &tag);
+            }
+        }
+
+        // Jumps to @underflow unless there are more than n values below n; n is compared
+        // unsigned, so a negative n fails too
+        fn pick_op(&mut self) {
+            let n = self.pop_value("l");
+            let depth = self.depth();
+            let c = self.new_tmp();
+            let offset = self.new_tmp();
+            let top = self.new_tmp();
+            let cell = self.new_tmp();
+            self.tmp += 1;
             let s = format!(
-                "\t%b =w c{}d %c_{}_{}, %c_{}_{}\n",
-                comp,
-                pos,
-                pos,
-                pos,
-                pos + 1
+                "\t{} =w cultl {}, {}\n\tjnz {}, @ok_{}, @underflow\n@ok_{}\n\t{} =l mul {}, {}\n\t{} =l sub %sp, {}\n\t{} =l sub {}, {}\n",
+                c, n, depth, c, self.tmp, self.tmp, offset, n, CELL_SIZE, top, CELL_SIZE, cell, top, offset
             );
-            self.output_file.write(s.as_bytes()).unwrap();
-            self.loop_stack += 1;
-            self.loop_end_stack = self.loop_stack;
+            self.emit(&s);
+            let v = self.load("l", &cell);
+            let tag = self.load_tag(&cell);
+            self.push_value("l", &v, &tag);
+        }
 
+        // Returns a temporary holding the number of values on the stack
+        fn depth(&mut self) -> String {
+            let bytes = self.new_tmp();
+            let depth = self.new_tmp();
             let s = format!(
-                "\tjnz %b, @loop_{}_{}, @end_loop_{}_{}\n@loop_{}_{}\n",
-                pos, self.loop_end_stack, pos, self.loop_end_stack, pos, self.loop_end_stack
+                "\t{} =l sub %sp, $stack\n\t{} =l udiv {}, {}\n",
+                bytes, depth, bytes, CELL_SIZE
             );
-            self.output_file.write(s.as_bytes()).unwrap();
-            Ok(0)
-        }
-
-        fn handle_end(
-            &mut self,
-            cur_block: EndBlock,
-            pos: i32,
-            cond_str: String,
-        ) -> Result<i32, String> {
-            let s: String;
-            match cur_block {
-                EndBlock::Cond => {
-                    if pos == 0 {
-                        s = format!(
-                            "@else_{}_{}\n@end_if_{}_{}\n",
-                            pos, self.if_end_stack, pos, self.if_end_stack
-                        );
-                    } else {
-                        s = format!("@end_if_{}_{}\n", pos, self.if_end_stack);
-                    }
-                    self.output_file.write(s.as_bytes()).unwrap();
-                    self.if_end_stack -= 1;
-                    if self.else_stack > 0 {
-                        self.else_stack -= 1;
-                    }
+            self.emit(&s);
+            depth
+        }
+
+        fn depth_op(&mut self) {
+            let depth = self.depth();
+            self.push_value("l", &depth, &TAG_INT.to_string());
+        }
+        // Variables hold the raw bits of a cell and its tag
+        fn set_op(&mut self, var: &str) {
+            let cell = self.pop_cell();
+            let v = self.load("l", &cell);
+            let tag = self.load_tag(&cell);
+            let s = format!(
+                "\t%s_{} =l copy {}\n\t%s_{}_tag =l copy {}\n",
+                mangle(var),
+                v,
+                mangle(var),
+                tag
+            );
+            self.emit(&s);
+        }
+
+        fn print_op(&mut self, op: &Builtin) {
+            let s: String = match op {
+                Builtin::Print => {
+                    let cell = self.pop_cell();
+                    format!("\tcall $rorth_print(l {})\n", cell)
                 }
-                EndBlock::Loop => {
-                    s = format!("\t%c_{}_{} =d sub %c_{}_{}, 1\n", pos, pos, pos, pos,);
-                    self.output_file.write(s.as_bytes()).unwrap();
-                    self.output_file.write(cond_str.as_bytes()).unwrap();
-                    let s = format!(
-                        "\tjnz %b, @loop_{}_{}, @end_loop_{}_{}\n@end_loop_{}_{}\n",
-                        pos,
-                        self.loop_end_stack,
-                        pos,
-                        self.loop_end_stack,
-                        pos,
-                        self.loop_end_stack
-                    );
-                    self.loop_end_stack -= 1;
-                    self.output_file.write(s.as_bytes()).unwrap();
+                Builtin::Peek => {
+                    let cell = self.peek_cell(1);
+                    format!("\tcall $rorth_print(l {})\n", cell)
                 }
-            }
-            Ok(0)
-        }
-
-        fn handle_word_call(&mut self, word: String) -> Result<i32, String> {
-            let mut cond_str = "".to_string();
-            let word_body = self.words.get(&word).unwrap().clone();
-            for i in 0..word_body.len() {
-                let res = self.handle_tokens(
-                    word_body[i].clone(),
-                    &mut cond_str,
-                    Peek::Word(word.clone(), i + 1),
-                );
-                if let Err(e) = res {
-                    return Err(e);
+                _ => {
+                    let v = self.pop_value("w");
+                    format!("\tcall $printf(l $fmt_char, ..., w {})\n", v)
                 }
+            };
+            self.emit(&s);
+        }
+
+        fn dbg_op(&mut self) {
+            self.emit("\tcall $rorth_dbg(l %sp)\n");
+        }
+
+        // Blocks every function ends with; reached when a push or pop would leave the stack,
+        // or on integer division by zero
+        fn error_blocks(ret: &str) -> String {
+            format!(
+                "@underflow\n\tcall $rorth_panic(l $err_underflow)\n\tret {}\n@overflow\n\tcall $rorth_panic(l $err_overflow)\n\tret {}\n@divzero\n\tcall $rorth_panic(l $err_divzero)\n\tret {}\n",
+                ret, ret, ret
+            )
+        }
+
+        // Generates `: name ... ;` as its own qbe function, `$w_name`, which takes the runtime
+        // stack pointer and returns it after the word has run
+        fn gen_word(&mut self, word: &WordDef) -> Result<(), Diagnostic> {
+            let main_body = std::mem::take(&mut self.body);
+            let res = self.gen_block(&word.body);
+            let function = format!(
+                "function l $w_{}(l %sp) {{\n@start\n{}@end\n\tret %sp\n{}}}\n",
+                mangle(&word.name),
+                self.body,
+                Self::error_blocks("%sp")
+            );
+            self.functions.push(function);
+            self.body = main_body;
+            res
+        }
+
+        // Pops the flag left by a condition and jumps to yes if it isn't 0
+        fn branch(&mut self, yes: &str, no: &str) {
+            let f = self.pop_value("l");
+            let b = self.new_tmp();
+            let s = format!("\t{} =w cnel {}, 0\n\tjnz {}, {}, {}\n", b, f, b, yes, no);
+            self.emit(&s);
+        }
+
+        fn gen_if(&mut self, then: &Block, els: &Option<Block>) -> Result<(), Diagnostic> {
+            self.tmp += 1;
+            let id = self.tmp;
+            self.branch(&format!("@if_{}", id), &format!("@else_{}", id));
+            self.emit(&format!("@if_{}\n", id));
+            self.gen_block(then)?;
+            self.emit(&format!("\tjmp @end_if_{}\n@else_{}\n", id, id));
+            if let Some(els) = els {
+                self.gen_block(els)?;
             }
-            Ok(0)
+            self.emit(&format!("@end_if_{}\n", id));
+            Ok(())
         }
 
-        fn parse_function_body(&mut self, end: TokenType) -> Result<i32, String> {
-            let mut cond_str = "".to_string();
-            while self.tokens[self.pos].tok_type != end {
-                let tok = self.tokens[self.pos].clone();
-                let res = self.handle_tokens(tok, &mut cond_str, Peek::Stack);
-                self.advance_token();
-                if let Err(e) = res {
-                    return Err(e);
-                }
+        // The condition is generated once and control jumps back to it after every iteration
+        fn gen_while(&mut self, cond: &Block, body: &Block) -> Result<(), Diagnostic> {
+            self.tmp += 1;
+            let id = self.tmp;
+            self.emit(&format!("\tjmp @loop_{}\n@loop_{}\n", id, id));
+            self.gen_block(cond)?;
+            self.branch(&format!("@body_{}", id), &format!("@end_loop_{}", id));
+            self.emit(&format!("@body_{}\n", id));
+            self.gen_block(body)?;
+            self.emit(&format!("\tjmp @loop_{}\n@end_loop_{}\n", id, id));
+            Ok(())
+        }
+
+        fn gen_block(&mut self, block: &Block) -> Result<(), Diagnostic> {
+            for node in block {
+                self.gen_node(node)?;
             }
-            Ok(0)
-        }
-
-        fn handle_tokens(
-            &mut self,
-            tok: Token,
-            cond_str: &mut String,
-            peek_target: Peek,
-        ) -> Result<i32, String> {
-            match &tok.tok_type {
-                TokenType::PLUS => match self.write_op("add") {
-                    Ok(i) => self.stack = i,
-                    Err(e) => return Err(e),
-                },
-                TokenType::MINUS => match self.write_op("sub") {
-                    Ok(i) => self.stack = i,
-                    Err(e) => return Err(e),
-                },
-                TokenType::ASTERISK => match self.write_op("mul") {
-                    Ok(i) => self.stack = i,
-                    Err(e) => return Err(e),
-                },
-                TokenType::SLASH => match self.write_op("div") {
-                    Ok(i) => self.stack = i,
-                    Err(e) => return Err(e),
-                },
-                TokenType::EQUAL => (self.stack, *cond_str) = self.comp_op("="),
-                TokenType::NEQUAL => (self.stack, *cond_str) = self.comp_op("!="),
-                TokenType::LTE => (self.stack, *cond_str) = self.comp_op("<="),
-                TokenType::LT => (self.stack, *cond_str) = self.comp_op("<"),
-                TokenType::GTE => (self.stack, *cond_str) = self.comp_op(">="),
-                TokenType::GT => (self.stack, *cond_str) = self.comp_op(">"),
-                TokenType::INT(_) => self.stack = self.push_op(&tok),
-                TokenType::STR(s) => {
-                    let var = Self::create_string_var(s.to_string());
-                    self.stack += 1;
-                    self.strings.insert(var.clone(), s.to_string());
-                    self.string_stack.insert(self.stack, var);
-                }
-                TokenType::DBG => self.dbg_op(),
-                TokenType::SEMICOLON => self.stack = 0,
-                TokenType::CHAR => match self.print_op(&tok) {
-                    Ok(s) => self.stack = s,
-                    Err(e) => return Err(e),
-                },
-                TokenType::PERIOD => match self.print_op(&tok) {
-                    Ok(s) => self.stack = s,
-                    Err(e) => return Err(e),
-                },
-                TokenType::COMMA => match self.print_op(&tok) {
-                    Ok(s) => self.stack = s + 1,
-                    Err(e) => return Err(e),
-                },
-               TokenType::SET => {
-                    let var = self.var_stack.last();
-                    if let Some(v) = var {
-                        self.stack = match self.set_op(v.clone(), tok) {
-                            Ok(i) => i,
-                            Err(e) => return Err(e),
-                        };
-                    } else {
-                        return self.format_err(&tok, "No variable to assign to".to_string());
-                    }
+            Ok(())
+        }
+
+        fn gen_node(&mut self, node: &Node) -> Result<(), Diagnostic> {
+            match &node.kind {
+                NodeKind::Literal(Literal::Int(n)) => {
+                    self.push_value("l", &n.to_string(), &TAG_INT.to_string())
                 }
-                TokenType::IF(pos) => {
-                    self.if_stack += 1;
-                    self.if_end_stack = self.if_stack;
-                    let s = format!(
-                        "\t%b =w dtosi %s_main_{}\n\tjnz %b, @if_{}_{}, @else_{}_{}\n@if_{}_{}\n",
-                        self.stack,
-                        pos,
-                        self.if_end_stack,
-                        pos,
-                        self.if_end_stack,
-                        pos,
-                        self.if_end_stack
-                    );
-                    self.output_file.write(s.as_bytes()).unwrap();
+                NodeKind::Literal(Literal::Float(f)) => {
+                    self.push_value("d", &format!("d_{:?}", f), &TAG_FLOAT.to_string())
                 }
-                TokenType::ELSE(pos) => {
-                    self.else_stack = self.if_end_stack;
-                    let s = format!(
-                        "\tjmp @end_if_{}_{}\n@else_{}_{}\n",
-                        pos, self.else_stack, pos, self.else_stack
-                    );
-                    self.output_file.write(s.as_bytes()).unwrap();
+                NodeKind::Literal(Literal::Str(s)) => {
+                    let var = self.intern(s);
+                    self.push_value("l", &var, &TAG_STR.to_string());
                 }
-                TokenType::WHILE(op, pos) => match self.handle_while(op.to_owned(), *pos, tok) {
-                    Ok(_) => (),
-                    Err(e) => return Err(e),
-                },
-                TokenType::END(cur_block, pos) => {
-                    match self.handle_end(cur_block.to_owned(), *pos, cond_str.to_owned()) {
-                        Ok(_) => (),
-                        Err(e) => return Err(e),
+                NodeKind::Builtin(b) => match b {
+                    Builtin::Add | Builtin::Sub | Builtin::Mul | Builtin::Div | Builtin::Mod => {
+                        self.math_op(b)
                     }
+                    Builtin::IntToFloat | Builtin::FloatToInt => self.conv_op(b),
+                    Builtin::Eq
+                    | Builtin::Ne
+                    | Builtin::Lt
+                    | Builtin::Le
+                    | Builtin::Gt
+                    | Builtin::Ge => self.comp_op(b),
+                    Builtin::And | Builtin::Or | Builtin::Not => self.logic_op(b),
+                    Builtin::Print | Builtin::Peek | Builtin::PrintChar => self.print_op(b),
+                    Builtin::Dbg => self.dbg_op(),
+                    Builtin::Pick => self.pick_op(),
+                    Builtin::Depth => self.depth_op(),
+                    _ => self.stack_op(b),
+                },
+                NodeKind::Var(v) => {
+                    let v = mangle(v);
+                    self.push_value("l", &format!("%s_{}", v), &format!("%s_{}_tag", v))
                 }
-                TokenType::COLON => {
-                    let res = self.new_word_op();
-                    if let Err(e) = res {
-                        return Err(e);
+                NodeKind::Assign(v) => self.set_op(v),
+                NodeKind::Call(w) => {
+                    if !self.words.contains(w) {
+                        return Err(self.format_err(node, format!("Invalid: {} undefined", w)));
                     }
+                    let s = format!("\t%sp =l call $w_{}(l %sp)\n", mangle(w));
+                    self.emit(&s)
                 }
-                TokenType::IDENT(ref s) => {
-                    if self.words.contains_key(s) {
-                        if self.peek(peek_target).tok_type == TokenType::SET {
-                            return self.format_err(
-                                &tok,
-                                format!("Invalid assignment: {:?} is a word, not a variable", tok),
-                            );
-                        }
-
-                        let res = self.handle_word_call(s.to_string());
-                        if let Err(e) = res {
-                            return Err(e);
-                        }
-                    } else {
-                        match self.vars.insert(s.to_string()) {
-                            true => {
-                                if self.peek(peek_target).tok_type != TokenType::SET {
-                                    return self
-                                        .format_err(&tok, format!("Invalid: {:?} undefined", tok));
-                                }
-                                self.var_stack.push(format!("s_{}", s))
-                            }
-                            false => {
-                                if self.peek(peek_target).tok_type != TokenType::SET {
-                                    self.stack = self.push_op(&tok);
-                                    self.var_stack.pop();
-                                }
-
-                                self.var_stack.push(format!("s_{}", s))
-                            }
-                        };
-                    }
+                NodeKind::If { then, els } => self.gen_if(then, els)?,
+                NodeKind::While { cond, body } => self.gen_while(cond, body)?,
+            }
+            Ok(())
+        }
+
+        // Items of a data definition holding s and its null terminator. Printable characters
+        // other than " and \ go between quotes, everything else is written as a byte
+        fn data_items(s: &str) -> String {
+            let mut items = vec![];
+            let mut run = String::new();
+            for b in s.bytes() {
+                if (b' '..=b'~').contains(&b) && b != b'"' && b != b'\\' {
+                    run.push(b as char);
+                    continue;
                 }
-                TokenType::LPAREN => (),
-                TokenType::RPAREN => (),
-                TokenType::EM => (),
-                TokenType::EOF => (),
-                _ => return self.format_err(&tok, format!("compiler: Unhandled token: {:?}", tok)),
+                if !run.is_empty() {
+                    items.push(format!("b \"{}\"", run));
+                    run.clear();
+                }
+                items.push(format!("b {}", b));
+            }
+            if !run.is_empty() {
+                items.push(format!("b \"{}\"", run));
             }
+            items.push("b 0".to_string());
+            items.join(", ")
+        }
+
+        // Returns the symbol of the data holding s, adding it the first time s is seen
+        fn intern(&mut self, s: &str) -> String {
+            let id = match self.string_ids.get(s) {
+                Some(id) => *id,
+                None => {
+                    self.strings.push(s.to_string());
+                    self.string_ids
+                        .insert(s.to_string(), self.strings.len() - 1);
+                    self.strings.len() - 1
+                }
+            };
+            format!("$str_{}", id)
+        }
 
-            Ok(0)
+        // Functions shared by all generated code: printing a cell according to its tag, dumping
+        // the whole stack for `dbg` and reporting runtime errors
+        fn runtime() -> String {
+            format!(
+                "function $rorth_print(l %cell) {{
+@start
+\t%tag_addr =l add %cell, 8
+\t%tag =l loadl %tag_addr
+\t%is_str =w ceql %tag, {}
+\tjnz %is_str, @str, @num
+@str
+\t%s =l loadl %cell
+\tcall $printf(l $fmt_str, ..., l %s)
+\tret
+@num
+\t%is_float =w ceql %tag, {}
+\tjnz %is_float, @float, @int
+@float
+\t%d =d loadd %cell
+\tcall $printf(l $fmt_float, ..., d %d)
+\tret
+@int
+\t%i =l loadl %cell
+\tcall $printf(l $fmt_int, ..., l %i)
+\tret
+}}
+function $rorth_dbg(l %sp) {{
+@start
+\tcall $printf(l $dbg, ...)
+\t%p =l copy $stack
+@loop
+\t%more =w cultl %p, %sp
+\tjnz %more, @body, @done
+@body
+\tcall $rorth_print(l %p)
+\t%p =l add %p, {}
+\tjmp @loop
+@done
+\tcall $printf(l $nl, ...)
+\tret
+}}
+function $rorth_panic(l %msg) {{
+@start
+\tcall $dprintf(w 2, l %msg, ...)
+\tcall $exit(w 1)
+\tret
+}}
+",
+                TAG_STR, TAG_FLOAT, CELL_SIZE
+            )
         }
 
-        fn create_string_var(k: String) -> String {
-            let s = k.split(",");
-            let mut var = "".to_string();
-            for i in s {
-                var.push_str(i.trim());
-                var.push_str("_");
+        // The whole qbe module: $main, a function for each word, the runtime and the data
+        fn output(&self) -> String {
+            let mut out = String::new();
+            out.push_str("export function w $main() {\n@start\n\t%sp =l copy $stack\n");
+            out.push_str(&self.body);
+            out.push_str("@end\n\tret 0\n");
+            out.push_str(&Self::error_blocks("1"));
+            out.push_str("}\n");
+            for f in &self.functions {
+                out.push_str(f);
             }
-            var
-        }
-        pub fn compile(&mut self) -> Result<i32, String> {
-            self.output_file
-                .write(b"export function w $main() {\n@start\n")
-                .unwrap();
-
-            self.cur_word = "main".to_string();
-            let res = self.parse_function_body(TokenType::EOF);
-
-            self.output_file.write(b"@end\n\tret 0\n}\n").unwrap();
-            self.output_file
-                .write(b"data $fmt_int = { b \"%.f \", b 0 }\n")
-                .unwrap();
-            self.output_file
-                .write(b"data $fmt_dec = { b \"%.10g \", b 0 }\n")
-                .unwrap();
-            self.output_file
-                .write(b"data $fmt_str = { b \"%s \", b 0 }\n")
-                .unwrap();
-            self.output_file
-                .write(b"data $fmt_char = { b \"%c\", b 0 }\n")
-                .unwrap();
-
-            self.output_file
-                .write(b"data $dbg = { b \"Debug: \", b 0 }\n")
-                .unwrap();
-            self.output_file
-                .write(b"data $nl = { b \"\\n\", b 0 }\n")
-                .unwrap();
-            for k in self.strings.keys() {
-                let v = format!(
-                    "data ${} = {{ b \"{}\", b 0 }}\n",
-                    k,
-                    self.strings[k]
-                )
-                .into_bytes();
-                self.output_file.write(&v).unwrap();
+            out.push_str(&Self::runtime());
+            let stack = format!("data $stack = {{ z {} }}\n", STACK_CELLS * CELL_SIZE);
+            out.push_str(&stack);
+            out.push_str("data $fmt_int = { b \"%lld \", b 0 }\n");
+            out.push_str("data $fmt_float = { b \"%f \", b 0 }\n");
+            out.push_str("data $fmt_str = { b \"%s \", b 0 }\n");
+            out.push_str("data $fmt_char = { b \"%c\", b 0 }\n");
+
+            out.push_str("data $dbg = { b \"Debug: \", b 0 }\n");
+            out.push_str("data $nl = { b \"\\n\", b 0 }\n");
+            out.push_str("data $err_underflow = { b \"Runtime error: Not enough values on the stack\\n\", b 0 }\n");
+            out.push_str("data $err_overflow = { b \"Runtime error: Stack overflow\\n\", b 0 }\n");
+            out.push_str("data $err_divzero = { b \"Runtime error: Division by zero\\n\", b 0 }\n");
+            for (i, s) in self.strings.iter().enumerate() {
+                let v = format!("data $str_{} = {{ {} }}\n", i, Self::data_items(s));
+                out.push_str(&v);
             }
+            out
+        }
 
-            let cmd = Command::new("sh")
-        .arg("-c")
-        .arg("qbe -o ./out/out.s ./out/rorth.ssa && gcc -o ./out/rorth ./out/out.s && ./out/rorth")
-        .output()
-        .expect("failed to execute command");
-            let output = cmd.stdout;
-            io::stdout().write_all(&output).unwrap();
-            let errout = cmd.stderr;
-            io::stdout().write_all(&errout).unwrap();
-            println!();
+        // Generates the qbe IL of the program
+        pub fn compile(&mut self, program: &Program) -> Result<String, Diagnostic> {
+            self.words = program.words.iter().map(|w| w.name.clone()).collect();
+            for word in &program.words {
+                self.gen_word(word)?;
+            }
+            self.gen_block(&program.main)?;
+            Ok(self.output())
+        }
+    }
 
-            res
+    // Runs a tool to completion, turning a failure to start it or a non-zero exit into an error
+    // that carries what it printed to stderr
+    fn run_tool(cmd: &mut Command) -> Result<(), String> {
+        let name = cmd.get_program().to_string_lossy().to_string();
+        let output = match cmd.output() {
+            Ok(o) => o,
+            Err(e) => return Err(format!("Failed to run {}: {}", name, e)),
+        };
+        if !output.status.success() {
+            return Err(format!(
+                "{} failed ({}):\n{}",
+                name,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            ));
+        }
+        Ok(())
+    }
+
+    // Writes the qbe IL to out_dir/bin_name.ssa
+    pub fn emit(ssa: &str, opts: &Options) -> Result<PathBuf, String> {
+        if let Err(e) = fs::create_dir_all(&opts.out_dir) {
+            return Err(format!("Error creating {}: {}", opts.out_dir.display(), e));
+        }
+        let path = opts.out_dir.join(format!("{}.ssa", opts.bin_name));
+        match fs::write(&path, ssa) {
+            Ok(_) => Ok(path),
+            Err(e) => Err(format!("Error writing {}: {}", path.display(), e)),
+        }
+    }
+
+    // Compiles the .ssa to assembly with qbe
+    pub fn assemble(ssa: &Path, opts: &Options) -> Result<PathBuf, String> {
+        let asm = opts.out_dir.join(format!("{}.s", opts.bin_name));
+        run_tool(Command::new(&opts.qbe).arg("-o").arg(&asm).arg(ssa))?;
+        Ok(asm)
+    }
+
+    // Assembles and links the assembly into out_dir/bin_name with cc
+    pub fn link(asm: &Path, opts: &Options) -> Result<PathBuf, String> {
+        let bin = opts.out_dir.join(&opts.bin_name);
+        run_tool(
+            Command::new(&opts.cc)
+                .arg("-o")
+                .arg(&bin)
+                .arg(asm)
+                .args(&opts.link_flags),
+        )?;
+        Ok(bin)
+    }
+
+    // Runs the binary with the terminal's stdin, stdout and stderr
+    pub fn run(bin: &Path) -> Result<(), String> {
+        let status = match Command::new(bin).status() {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Failed to run {}: {}", bin.display(), e)),
+        };
+        if !status.success() {
+            return Err(format!("{} exited with {}", bin.display(), status));
+        }
+        Ok(())
+    }
+
+    // Why a build failed
+    #[derive(Debug)]
+    pub enum BuildError {
+        // The program can't be compiled to qbe IL
+        Program(Diagnostic),
+        // Writing the .ssa or running qbe or cc failed
+        Tool(String),
+        // The binary was built but failed while it ran
+        Run(String),
+    }
+
+    // Compiles the program to a binary, and runs it if opts.run is set, passing a message to
+    // on_step after each step. Returns the path of the binary
+    pub fn build(
+        program: &Program,
+        opts: &Options,
+        on_step: &dyn Fn(String),
+    ) -> Result<PathBuf, BuildError> {
+        let ssa = new().compile(program).map_err(BuildError::Program)?;
+        let ssa_path = emit(&ssa, opts).map_err(BuildError::Tool)?;
+        on_step(format!("wrote {}", ssa_path.display()));
+        let asm = assemble(&ssa_path, opts).map_err(BuildError::Tool)?;
+        on_step(format!("{} wrote {}", opts.qbe, asm.display()));
+        let bin = link(&asm, opts).map_err(BuildError::Tool)?;
+        on_step(format!("{} linked {}", opts.cc, bin.display()));
+        if opts.run {
+            on_step(format!("running {}", bin.display()));
+            run(&bin).map_err(BuildError::Run)?;
         }
+        Ok(bin)
     }
 }
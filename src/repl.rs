@@ -0,0 +1,237 @@
+pub mod repl {
+    use std::{
+        cell::Cell,
+        fs,
+        io::{self, BufRead, Write},
+        path::PathBuf,
+        rc::Rc,
+    };
+
+    use crate::{
+        ast::ast::Signature,
+        diagnostic::diagnostic::Diagnostic,
+        interpreter::interpreter::{self, Interpreter, Value},
+        lexer::lexer::{self, TokenType},
+        parser::parser::{self, Parser},
+        typechecker::typechecker::{self, Type, TypeChecker},
+    };
+
+    // Name diagnostics give for code typed at the prompt
+    const FILE: &str = "<repl>";
+
+    const HELP: &str = "\
+.words        list the words defined so far
+.clear        empty the stack
+.load <file>  run a file, keeping its words and variables
+.help         show this message
+Ctrl-D quits";
+
+    // Stdout that remembers whether the last thing written ended a line, so the stack can be
+    // printed on a line of its own after the program's output
+    struct Stdout(Rc<Cell<bool>>);
+
+    impl Write for Stdout {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = io::stdout().write(buf)?;
+            if n > 0 {
+                self.0.set(buf[n - 1] != b'\n');
+            }
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            io::stdout().flush()
+        }
+    }
+
+    // Everything defined so far. Each piece of code is parsed and type checked against what
+    // the code before it left, and a piece that fails leaves the session as it was
+    pub struct Repl {
+        include: Vec<PathBuf>,
+        parser: Parser,
+        checker: TypeChecker,
+        interpreter: Interpreter,
+        // Words in the order they were defined
+        words: Vec<(String, Option<Signature>)>,
+        // Set when the program printed something without ending the line
+        partial_line: Rc<Cell<bool>>,
+    }
+
+    pub fn new(include: Vec<PathBuf>) -> Repl {
+        let partial_line = Rc::new(Cell::new(false));
+        let mut i = interpreter::new();
+        i.set_output(Box::new(Stdout(partial_line.clone())));
+        Repl {
+            include,
+            parser: parser::new(vec![]),
+            checker: typechecker::new(),
+            interpreter: i,
+            words: vec![],
+            partial_line,
+        }
+    }
+
+    fn type_of(v: &Value) -> Type {
+        match v {
+            Value::Int(_) => Type::Int,
+            Value::Float(_) => Type::Float,
+            Value::Str(_) => Type::Str,
+        }
+    }
+
+    // Whether source opens a word definition it doesn't close yet
+    fn open_word(source: &str) -> bool {
+        let mut l = lexer::new(FILE.to_string(), source.to_string());
+        let _ = l.lex();
+        let mut depth = 0;
+        for t in &l.tokens {
+            match t.tok_type {
+                TokenType::COLON => depth += 1,
+                TokenType::SEMICOLON if depth > 0 => depth -= 1,
+                _ => {}
+            }
+        }
+        depth > 0
+    }
+
+    impl Repl {
+        // Runs source, which is named file in diagnostics
+        pub fn eval(&mut self, file: &str, source: &str) -> Result<(), Vec<Diagnostic>> {
+            let mut l = lexer::new(file.to_string(), source.to_string());
+            l.set_include(self.include.clone());
+            for (name, _) in &self.words {
+                l.define(name);
+            }
+            let mut errors = l.lex().err().unwrap_or_default();
+
+            let (parser, checker) = (self.parser.clone(), self.checker.clone());
+            let (stack, vars) = (
+                self.interpreter.stack.clone(),
+                self.interpreter.vars().clone(),
+            );
+            self.parser.resume(l.tokens, stack.len());
+            let res = match self.parser.parse() {
+                Ok(program) if errors.is_empty() => {
+                    let words: Vec<_> = program
+                        .words
+                        .iter()
+                        .map(|w| (w.name.clone(), w.signature.clone()))
+                        .collect();
+                    self.checker.set_stack(stack.iter().map(type_of).collect());
+                    let res = match self.checker.check(&program) {
+                        Ok(()) => self.interpreter.interpret(program),
+                        Err(e) => Err(e),
+                    };
+                    if res.is_ok() {
+                        for (name, sig) in words {
+                            self.words.retain(|(n, _)| *n != name);
+                            self.words.push((name, sig));
+                        }
+                    }
+                    res.map_err(|e| vec![e])
+                }
+                Ok(_) => Err(errors),
+                Err(e) => {
+                    errors.extend(e);
+                    Err(errors)
+                }
+            };
+            if res.is_err() {
+                self.parser = parser;
+                self.checker = checker;
+                self.interpreter.stack = stack;
+                *self.interpreter.vars() = vars;
+            }
+            res
+        }
+
+        // Handles a line starting with one of the commands; returns false for anything else, like
+        // . to print the top of the stack
+        fn command(&mut self, line: &str) -> bool {
+            let (cmd, arg) = line.split_once(' ').unwrap_or((line, ""));
+            match cmd {
+                ".words" => {
+                    for (name, sig) in &self.words {
+                        match sig {
+                            Some(s) => println!("{} {}", name, s),
+                            None => println!("{}", name),
+                        }
+                    }
+                }
+                ".clear" => self.interpreter.stack.clear(),
+                ".load" => {
+                    let path = arg.trim();
+                    match fs::read_to_string(path) {
+                        Ok(source) => {
+                            let res = self.eval(path, &source);
+                            self.report(res, &source);
+                        }
+                        Err(e) => eprintln!("error: Error reading {}: {}", path, e),
+                    }
+                }
+                ".help" => println!("{}", HELP),
+                _ => return false,
+            }
+            true
+        }
+
+        fn report(&mut self, res: Result<(), Vec<Diagnostic>>, source: &str) {
+            if self.partial_line.replace(false) {
+                println!();
+            }
+            if let Err(errors) = res {
+                for d in errors {
+                    // Imports are read again to show the line
+                    let src = match d.file.as_str() {
+                        FILE => Some(source.to_string()),
+                        f => fs::read_to_string(f).ok(),
+                    };
+                    eprint!("{}", d.render(src.as_deref()));
+                }
+            }
+        }
+
+        // Prints the stack, deepest first, after the number of values on it
+        fn print_stack(&self) {
+            let mut s = format!("<{}>", self.interpreter.stack.len());
+            for v in &self.interpreter.stack {
+                match v {
+                    Value::Str(_) => s.push_str(&format!(" {:?}", v.to_string())),
+                    _ => s.push_str(&format!(" {}", v)),
+                }
+            }
+            println!("{}", s);
+        }
+
+        // Reads code from stdin until it ends, running each line and printing the stack after
+        // it. A line that opens a word definition waits for the lines up to its ;
+        pub fn run(&mut self) -> io::Result<()> {
+            let stdin = io::stdin();
+            let mut lines = stdin.lock().lines();
+            let mut source = String::new();
+            loop {
+                print!("{}", if source.is_empty() { "> " } else { ".. " });
+                io::stdout().flush()?;
+                let line = match lines.next() {
+                    Some(l) => l?,
+                    None => break,
+                };
+                if source.is_empty() && self.command(line.trim()) {
+                    self.print_stack();
+                    continue;
+                }
+                source.push_str(&line);
+                source.push('\n');
+                if open_word(&source) {
+                    continue;
+                }
+                let res = self.eval(FILE, &source);
+                self.report(res, &source);
+                self.print_stack();
+                source.clear();
+            }
+            println!();
+            Ok(())
+        }
+    }
+}
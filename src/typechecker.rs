@@ -0,0 +1,455 @@
+pub mod typechecker {
+    use std::{collections::HashMap, fmt, rc::Rc};
+
+    use crate::{
+        ast::ast::{Block, Builtin, Literal, Node, NodeKind, Program, WordDef},
+        diagnostic::diagnostic::{self, Diagnostic},
+        parser::parser::MAX_DEPTH,
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Type {
+        Int,
+        Float,
+        // Left by comparisons
+        Bool,
+        Str,
+        // A value whose type depends on the data, which matches any type
+        Any,
+    }
+
+    impl fmt::Display for Type {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let s = match self {
+                Type::Int => "int",
+                Type::Float => "float",
+                Type::Bool => "bool",
+                Type::Str => "str",
+                Type::Any => "any",
+            };
+            write!(f, "{}", s)
+        }
+    }
+
+    const INTS: &[&[Type]] = &[&[Type::Int, Type::Int]];
+    const NUMS: &[&[Type]] = &[&[Type::Int, Type::Int], &[Type::Float, Type::Float]];
+    const EQUATABLE: &[&[Type]] = &[
+        &[Type::Int, Type::Int],
+        &[Type::Float, Type::Float],
+        &[Type::Bool, Type::Bool],
+    ];
+    const FLAG: &[&[Type]] = &[&[Type::Bool], &[Type::Int]];
+
+    // How many blocks, counting word bodies, the checker goes into before it checks calls
+    // against the callee's signature instead of its body. Blocks alone nest up to MAX_DEPTH
+    const MAX_CHECK_DEPTH: usize = 2 * MAX_DEPTH;
+
+    // Types of the stack and the variables at one point of the program
+    #[derive(Debug, Clone, PartialEq, Default)]
+    struct State {
+        // Top of the stack last
+        stack: Vec<Type>,
+        // Set when the values below stack are unknown, like the inputs of a word or the stack
+        // after a block whose depth depends on the data
+        open: bool,
+        vars: HashMap<String, Type>,
+    }
+
+    impl State {
+        // State after code that can reach the same point with either self or other
+        fn merge(&self, other: &State) -> State {
+            let (stack, open) = if self.stack.len() == other.stack.len() {
+                let stack = self
+                    .stack
+                    .iter()
+                    .zip(&other.stack)
+                    .map(|(a, b)| if a == b { *a } else { Type::Any })
+                    .collect();
+                (stack, self.open || other.open)
+            } else {
+                (vec![], true)
+            };
+            let mut vars = HashMap::new();
+            for (k, a) in &self.vars {
+                let t = match other.vars.get(k) {
+                    Some(b) if a == b => *a,
+                    _ => Type::Any,
+                };
+                vars.insert(k.clone(), t);
+            }
+            for k in other.vars.keys() {
+                vars.entry(k.clone()).or_insert(Type::Any);
+            }
+            State { stack, open, vars }
+        }
+    }
+
+    // Types of a stack, and whether it's open
+    type Stack = (Vec<Type>, bool);
+
+    #[derive(Clone)]
+    pub struct TypeChecker {
+        // HashMap in the form "word name": definition
+        words: HashMap<String, Rc<WordDef>>,
+        // Words whose bodies are being checked, innermost last; calling one of them again is
+        // recursion, so its signature is used instead of its body
+        calls: Vec<String>,
+        // Blocks the checker is inside of, counting the bodies of called words
+        depth: usize,
+        state: State,
+        // Result of checking a word's body, by name and the stack it was checked with; each
+        // body is checked once per stack, not once per call
+        checked: HashMap<(String, Stack), Result<Stack, Diagnostic>>,
+        // State left by the code outside of words, where the next program checked starts
+        main: State,
+    }
+
+    pub fn new() -> TypeChecker {
+        TypeChecker {
+            words: HashMap::new(),
+            calls: vec![],
+            depth: 0,
+            state: State::default(),
+            checked: HashMap::new(),
+            main: State::default(),
+        }
+    }
+
+    impl TypeChecker {
+        fn format_err(&self, node: &Node, message: String) -> Diagnostic {
+            diagnostic::error(&node.span, message)
+        }
+
+        fn pop(&mut self) -> Option<Type> {
+            match self.state.stack.pop() {
+                Some(t) => Some(t),
+                None if self.state.open => Some(Type::Any),
+                None => None,
+            }
+        }
+
+        // Pops as many values as each alternative in expected has, deepest first, and checks
+        // that their types match one of them
+        fn expect(
+            &mut self,
+            node: &Node,
+            name: &str,
+            expected: &[&[Type]],
+        ) -> Result<Vec<Type>, Diagnostic> {
+            let mut got = vec![];
+            for _ in 0..expected[0].len() {
+                match self.pop() {
+                    Some(t) => got.insert(0, t),
+                    None => {
+                        return Err(self.format_err(
+                            node,
+                            format!("Invalid `{}`: Not enough values on the stack", name),
+                        ))
+                    }
+                }
+            }
+            let matches = |types: &&[Type]| {
+                types
+                    .iter()
+                    .zip(&got)
+                    .all(|(e, g)| e == g || *e == Type::Any || *g == Type::Any)
+            };
+            if !expected.iter().any(matches) {
+                let join = |types: &[Type]| {
+                    let names: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+                    names.join(" ")
+                };
+                let alts: Vec<String> = expected.iter().map(|e| join(e)).collect();
+                return Err(self.format_err(
+                    node,
+                    format!(
+                        "`{}` expects {}, got {}",
+                        name,
+                        alts.join(" or "),
+                        join(&got)
+                    ),
+                ));
+            }
+            Ok(got)
+        }
+
+        fn builtin(&mut self, node: &Node, b: &Builtin) -> Result<(), Diagnostic> {
+            let name = b.to_string();
+            let (req, _) = b.effect();
+            let mut push = match b {
+                // Both operands have the same type, unless one of them is any
+                Builtin::Add | Builtin::Sub | Builtin::Mul | Builtin::Div => {
+                    let args = self.expect(node, &name, NUMS)?;
+                    match args.iter().find(|t| **t != Type::Any) {
+                        Some(t) => vec![*t],
+                        None => vec![Type::Any],
+                    }
+                }
+                Builtin::Mod => {
+                    self.expect(node, &name, INTS)?;
+                    vec![Type::Int]
+                }
+                Builtin::IntToFloat => {
+                    self.expect(node, &name, &[&[Type::Int]])?;
+                    vec![Type::Float]
+                }
+                Builtin::FloatToInt => {
+                    self.expect(node, &name, &[&[Type::Float]])?;
+                    vec![Type::Int]
+                }
+                Builtin::Eq | Builtin::Ne => {
+                    let mut args = self.expect(node, &name, EQUATABLE)?;
+                    args.push(Type::Bool);
+                    args
+                }
+                Builtin::Lt | Builtin::Le | Builtin::Gt | Builtin::Ge => {
+                    let mut args = self.expect(node, &name, NUMS)?;
+                    args.push(Type::Bool);
+                    args
+                }
+                Builtin::And | Builtin::Or => {
+                    self.expect(
+                        node,
+                        &name,
+                        &[&[Type::Bool, Type::Bool], &[Type::Int, Type::Int]],
+                    )?;
+                    vec![Type::Bool]
+                }
+                Builtin::Not => {
+                    self.expect(node, &name, FLAG)?;
+                    vec![Type::Bool]
+                }
+                Builtin::PrintChar => {
+                    self.expect(node, &name, &[&[Type::Int]])?;
+                    vec![]
+                }
+                // The type of the value picked depends on n
+                Builtin::Pick => {
+                    self.expect(node, &name, &[&[Type::Int]])?;
+                    vec![Type::Any]
+                }
+                Builtin::Depth => vec![Type::Int],
+                _ => {
+                    let any = vec![Type::Any; req as usize];
+                    let args = self.expect(node, &name, &[&any])?;
+                    match b {
+                        Builtin::Print | Builtin::Dbg | Builtin::Drop | Builtin::TwoDrop => vec![],
+                        Builtin::Peek => args,
+                        Builtin::Dup => vec![args[0], args[0]],
+                        Builtin::Swap => vec![args[1], args[0]],
+                        Builtin::Nip => vec![args[1]],
+                        Builtin::Over => vec![args[0], args[1], args[0]],
+                        Builtin::TwoDup => vec![args[0], args[1], args[0], args[1]],
+                        Builtin::Tuck => vec![args[1], args[0], args[1]],
+                        _ => vec![args[1], args[2], args[0]],
+                    }
+                }
+            };
+            self.state.stack.append(&mut push);
+            Ok(())
+        }
+
+        fn call(&mut self, node: &Node, name: &str) -> Result<(), Diagnostic> {
+            let word = match self.words.get(name) {
+                Some(w) => w.clone(),
+                None => return Err(self.format_err(node, format!("Invalid: {} undefined", name))),
+            };
+            if self.calls.iter().any(|c| c == name) {
+                match word.signature.as_ref().and_then(|s| s.effect()) {
+                    Some((req, out)) => {
+                        for _ in 0..req {
+                            self.pop();
+                        }
+                        for _ in 0..out {
+                            self.state.stack.push(Type::Any);
+                        }
+                    }
+                    None => {
+                        self.state.stack.clear();
+                        self.state.open = true;
+                    }
+                }
+                return Ok(());
+            }
+            if self.depth >= MAX_CHECK_DEPTH {
+                let (req, out) = match word.signature.as_ref().and_then(|s| s.effect()) {
+                    Some(effect) => effect,
+                    None => {
+                        return Err(self.format_err(
+                            node,
+                            format!(
+                                "Calls nested too deeply to check; give {} a signature",
+                                name
+                            ),
+                        ))
+                    }
+                };
+                let any = vec![Type::Any; req as usize];
+                self.expect(node, name, &[&any])?;
+                self.state.stack.extend(vec![Type::Any; out as usize]);
+                return Ok(());
+            }
+            // The body is checked with the types the caller leaves on the stack: the values the
+            // signature takes, or the whole stack for a word without a fixed effect. The values
+            // below the ones taken may still be there, so the stack is open over them
+            let effect = word.signature.as_ref().and_then(|s| s.effect());
+            let split = match effect {
+                Some((req, _)) => self.state.stack.len().saturating_sub(req as usize),
+                None => 0,
+            };
+            let inputs = self.state.stack[split..].to_vec();
+            let key = (name.to_string(), (inputs, self.state.open || split > 0));
+            let res = match self.checked.get(&key) {
+                Some(res) => res.clone(),
+                None => {
+                    let res = self.check_body(&word, &key.1, effect);
+                    self.checked.insert(key, res.clone());
+                    res
+                }
+            };
+            match res {
+                Ok((stack, open)) => {
+                    self.state.stack.truncate(split);
+                    self.state.stack.extend(stack);
+                    self.state.open |= open;
+                    Ok(())
+                }
+                Err(e) => Err(e.with_note(format!(
+                    "in the call to {} at {}:{}:{}",
+                    name, node.span.file, node.row, node.col
+                ))),
+            }
+        }
+
+        // Checks the body of a word on the stack input, and returns the stack it leaves. A word
+        // with a fixed effect leaves as many values as its signature says
+        fn check_body(
+            &mut self,
+            word: &WordDef,
+            input: &Stack,
+            effect: Option<(i32, i32)>,
+        ) -> Result<Stack, Diagnostic> {
+            let caller = std::mem::replace(
+                &mut self.state,
+                State {
+                    stack: input.0.clone(),
+                    open: input.1,
+                    vars: HashMap::new(),
+                },
+            );
+            self.calls.push(word.name.clone());
+            let res = self.check_block(&word.body);
+            self.calls.pop();
+            let after = std::mem::replace(&mut self.state, caller);
+            res?;
+            Ok(match effect {
+                Some((_, out)) if after.stack.len() == out as usize => (after.stack, false),
+                Some((_, out)) => (vec![Type::Any; out as usize], false),
+                None => (after.stack, after.open),
+            })
+        }
+
+        // The second iteration is checked with the exact types the first one leaves, so a
+        // variable that changes type is caught; after that, iterations are checked with the
+        // types merged until they stop changing
+        fn check_loop(
+            &mut self,
+            node: &Node,
+            cond: &Block,
+            body: &Block,
+        ) -> Result<(), Diagnostic> {
+            let mut exit: Option<State> = None;
+            let mut first = true;
+            loop {
+                let start = self.state.clone();
+                self.check_block(cond)?;
+                self.expect(node, "while", FLAG)?;
+                exit = Some(match exit {
+                    Some(e) => e.merge(&self.state),
+                    None => self.state.clone(),
+                });
+                self.check_block(body)?;
+                let next = start.merge(&self.state);
+                if next == start {
+                    break;
+                }
+                if !first {
+                    self.state = next;
+                }
+                first = false;
+            }
+            self.state = exit.unwrap_or_default();
+            Ok(())
+        }
+
+        fn check_block(&mut self, block: &Block) -> Result<(), Diagnostic> {
+            self.depth += 1;
+            let res = block.iter().try_for_each(|node| self.check_node(node));
+            self.depth -= 1;
+            res
+        }
+
+        fn check_node(&mut self, node: &Node) -> Result<(), Diagnostic> {
+            match &node.kind {
+                NodeKind::Literal(Literal::Int(_)) => self.state.stack.push(Type::Int),
+                NodeKind::Literal(Literal::Float(_)) => self.state.stack.push(Type::Float),
+                NodeKind::Literal(Literal::Str(_)) => self.state.stack.push(Type::Str),
+                NodeKind::Builtin(b) => self.builtin(node, b)?,
+                NodeKind::Var(v) => {
+                    let t = self.state.vars.get(v).copied().unwrap_or(Type::Any);
+                    self.state.stack.push(t);
+                }
+                NodeKind::Assign(v) => {
+                    let t = self.expect(node, ":=", &[&[Type::Any]])?[0];
+                    self.state.vars.insert(v.clone(), t);
+                }
+                NodeKind::Call(w) => self.call(node, w)?,
+                NodeKind::If { then, els } => {
+                    self.expect(node, "if", FLAG)?;
+                    let start = self.state.clone();
+                    self.check_block(then)?;
+                    let after_then = std::mem::replace(&mut self.state, start);
+                    if let Some(els) = els {
+                        self.check_block(els)?;
+                    }
+                    self.state = after_then.merge(&self.state);
+                }
+                NodeKind::While { cond, body } => self.check_loop(node, cond, body)?,
+            }
+            Ok(())
+        }
+
+        // Checks every word on its own, with inputs of any type, then the code outside of words
+        pub fn check(&mut self, program: &Program) -> Result<(), Diagnostic> {
+            // A word defined again changes what calls to it leave
+            if !program.words.is_empty() {
+                self.checked.clear();
+            }
+            for word in &program.words {
+                self.words.insert(word.name.clone(), Rc::new(word.clone()));
+            }
+            for word in &program.words {
+                self.state = State {
+                    open: true,
+                    ..State::default()
+                };
+                self.calls = vec![word.name.clone()];
+                self.check_block(&word.body)?;
+            }
+            self.state = self.main.clone();
+            self.calls = vec![];
+            self.check_block(&program.main)?;
+            self.main = std::mem::take(&mut self.state);
+            Ok(())
+        }
+
+        // Gives the code outside of words the types of the values on the stack, when the
+        // types checked so far don't say what they are; the repl keeps its stack between lines
+        pub fn set_stack(&mut self, stack: Vec<Type>) {
+            if self.main.open || self.main.stack.len() != stack.len() {
+                self.main.stack = stack;
+                self.main.open = false;
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+pub mod value {
+    use std::fmt::Debug;
+
+    // A value on the stack of the interpreter or the vm; S is how each of them keeps strings
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Value<S> {
+        Int(i64),
+        Float(f64),
+        Str(S),
+    }
+
+    // Ops that work the same in the interpreter and the vm, whatever they're called there
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Prim {
+        Add,
+        Sub,
+        Mul,
+        Div,
+        Mod,
+        IntToFloat,
+        FloatToInt,
+        Eq,
+        Ne,
+        Lt,
+        Le,
+        Gt,
+        Ge,
+        And,
+        Or,
+        Not,
+    }
+
+    // The value stack, with the ops in Prim run on it. Errors name the op the way the
+    // interpreter or the vm calls it
+    pub trait Machine<S> {
+        fn stack(&mut self) -> &mut Vec<Value<S>>;
+
+        fn pop(&mut self, op: &impl Debug) -> Result<Value<S>, String> {
+            match self.stack().pop() {
+                Some(v) => Ok(v),
+                None => Err(format!("Invalid {:?}: Not enough values on the stack", op)),
+            }
+        }
+
+        fn pop_int(&mut self, op: &impl Debug) -> Result<i64, String> {
+            match self.pop(op)? {
+                Value::Int(i) => Ok(i),
+                v => Err(type_err(op, &v)),
+            }
+        }
+
+        // Index of the top of the stack, once there are at least `depth` values on it
+        fn top(&mut self, op: &impl Debug, depth: usize) -> Result<usize, String> {
+            let len = self.stack().len();
+            if len < depth {
+                return Err(format!("Invalid {:?}: Not enough values on the stack", op));
+            }
+            Ok(len - 1)
+        }
+
+        fn prim_op(&mut self, op: &impl Debug, p: Prim) -> Result<(), String> {
+            let v = match p {
+                Prim::Add | Prim::Sub | Prim::Mul | Prim::Div | Prim::Mod => {
+                    let b = self.pop(op)?;
+                    let a = self.pop(op)?;
+                    math(op, p, a, b)?
+                }
+                Prim::IntToFloat | Prim::FloatToInt => match (p, self.pop(op)?) {
+                    (Prim::IntToFloat, Value::Int(i)) => Value::Float(i as f64),
+                    (Prim::FloatToInt, Value::Float(f)) => Value::Int(f as i64),
+                    (_, v) => return Err(type_err(op, &v)),
+                },
+                // Comparisons leave both operands on the stack and push 1 or 0
+                Prim::Eq | Prim::Ne | Prim::Lt | Prim::Le | Prim::Gt | Prim::Ge => {
+                    let sp = self.top(op, 2)?;
+                    let stack = self.stack();
+                    Value::Int(compare(op, p, &stack[sp - 1], &stack[sp])? as i64)
+                }
+                Prim::And | Prim::Or | Prim::Not => {
+                    let b = self.pop_int(op)? != 0;
+                    let res = match p {
+                        Prim::Not => !b,
+                        Prim::And => self.pop_int(op)? != 0 && b,
+                        _ => self.pop_int(op)? != 0 || b,
+                    };
+                    Value::Int(res as i64)
+                }
+            };
+            self.stack().push(v);
+            Ok(())
+        }
+    }
+
+    pub fn type_err<S>(op: &impl Debug, v: &Value<S>) -> String {
+        let ty = match v {
+            Value::Int(_) => "ints",
+            Value::Float(_) => "floats",
+            Value::Str(_) => "strings",
+        };
+        format!("Invalid types for {:?}; can't use {:?} with {}", op, op, ty)
+    }
+
+    // Ints wrap around instead of overflowing, so i64::MIN / -1 is i64::MIN
+    fn math<S>(op: &impl Debug, p: Prim, a: Value<S>, b: Value<S>) -> Result<Value<S>, String> {
+        match (a, b) {
+            (Value::Int(a), Value::Int(b)) => {
+                if b == 0 && (p == Prim::Div || p == Prim::Mod) {
+                    return Err(format!("Invalid {:?}: Division by zero", op));
+                }
+                Ok(Value::Int(match p {
+                    Prim::Add => a.wrapping_add(b),
+                    Prim::Sub => a.wrapping_sub(b),
+                    Prim::Mul => a.wrapping_mul(b),
+                    Prim::Div => a.wrapping_div(b),
+                    _ => a.wrapping_rem(b),
+                }))
+            }
+            (Value::Float(a), Value::Float(b)) if p != Prim::Mod => Ok(Value::Float(match p {
+                Prim::Add => a + b,
+                Prim::Sub => a - b,
+                Prim::Mul => a * b,
+                _ => a / b,
+            })),
+            (Value::Int(_), v) | (v, _) => Err(type_err(op, &v)),
+        }
+    }
+
+    fn compare<S>(op: &impl Debug, p: Prim, a: &Value<S>, b: &Value<S>) -> Result<bool, String> {
+        let ord = match (a, b) {
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Int(_), v) | (v, _) => return Err(type_err(op, v)),
+        };
+        // NaN is unordered, so only != holds for it
+        Ok(match ord {
+            Some(o) => match p {
+                Prim::Eq => o.is_eq(),
+                Prim::Ne => o.is_ne(),
+                Prim::Lt => o.is_lt(),
+                Prim::Le => o.is_le(),
+                Prim::Gt => o.is_gt(),
+                _ => o.is_ge(),
+            },
+            None => p == Prim::Ne,
+        })
+    }
+}
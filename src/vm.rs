@@ -1,165 +1,547 @@
 pub mod vm {
-    use crate::op::op::Op;
+    use std::rc::Rc;
+
+    use crate::{
+        ast::ast::Builtin,
+        diagnostic::diagnostic::{self, Diagnostic, Span},
+        op::op::{decode, read_sleb, read_uleb, Op, RVM_FLAG_DEBUG, RVM_MAGIC, RVM_VERSION},
+        value::value::{self, Machine, Prim},
+    };
+
+    // Strings are the index of their first character in the constant pool, where they end at a 0
+    pub type Value = value::Value<usize>;
+
+    // Maximum depth of nested word calls before the VM gives up
+    const MAX_FRAMES: usize = 1 << 16;
+
+    // Activation record for a word call
+    struct Frame {
+        // Byte offset to continue from when the word returns
+        ret: usize,
+        vars: Vec<Option<Value>>,
+    }
 
     pub struct VM {
-        bytes: Vec<Op>,
+        // Bytecode produced by op::encode
+        code: Vec<u8>,
+        // Byte offset of the next op to decode
         ip: usize,
-        sp: usize,
-        stack: Vec<i64>,
+        // Byte offset of the op being executed
+        op_start: usize,
+        stack: Vec<Value>,
         mem_stack: Vec<i64>,
+        // Return stack; the bottom frame holds the variables of the main program
+        frames: Vec<Frame>,
+        // Empty unless provided by the compiler or a .rvm debug section
+        debug_info: DebugInfo,
+    }
+
+    // Debug info for the bytecode, each table sorted by byte offset
+    #[derive(Debug, Default)]
+    pub struct DebugInfo {
+        // (byte offset of an op, source it was generated from)
+        pub spans: Vec<(usize, Span)>,
+        // (byte offset of a LOAD, name of the variable it loads)
+        pub vars: Vec<(usize, String)>,
+    }
+
+    impl Machine<usize> for VM {
+        fn stack(&mut self) -> &mut Vec<Value> {
+            &mut self.stack
+        }
     }
 
     impl VM {
-        fn math_op(&mut self, op: &Op) -> Result<(), String> {
-            let b = self.stack.pop();
-            self.sp -= 1;
-            match b {
-                Some(b) => match op {
-                    Op::ADD => self.stack[self.sp] = self.stack[self.sp] + b,
-                    Op::SUB => self.stack[self.sp] = self.stack[self.sp] - b,
-                    Op::MUL => self.stack[self.sp] = self.stack[self.sp] * b,
-                    Op::DIV => self.stack[self.sp] = self.stack[self.sp] / b,
-                    _ => {}
-                },
-                None => return Err(format!("Invalid {:?}: Not enough values on the stack", op)),
+        pub fn set_debug(&mut self, debug_info: DebugInfo) {
+            self.debug_info = debug_info;
+        }
+
+        // Points a runtime error at the source of the current op, if known
+        fn locate(&self, e: String) -> Diagnostic {
+            let spans = &self.debug_info.spans;
+            match spans.binary_search_by_key(&self.op_start, |s| s.0) {
+                Ok(i) => diagnostic::error(&spans[i].1, e),
+                Err(_) => e.into(),
             }
-            Ok(())
         }
 
-        fn pushnum_op(&mut self, mem_idx: Option<usize>) -> Result<(), String> {
-            if let Some(n) = mem_idx {
-                self.stack.push(self.mem_stack[n]);
-            } else {
-                self.stack.push(self.stack[self.sp]);
+        fn jump_op(&mut self, op: &Op) -> Result<(), String> {
+            match *op {
+                Op::JMP(n) => self.ip = n,
+                Op::JZ(n) => {
+                    if self.pop_int(op)? == 0 {
+                        self.ip = n;
+                    }
+                }
+                Op::JNZ(n) => {
+                    if self.pop_int(op)? != 0 {
+                        self.ip = n;
+                    }
+                }
+                _ => unreachable!(),
             }
-            self.sp = self.stack.len() - 1;
+            Ok(())
+        }
+
+        fn pushnum_op(&mut self, mem_idx: usize) -> Result<(), String> {
+            self.stack.push(Value::Int(self.mem_stack[mem_idx]));
+            Ok(())
+        }
+
+        fn pushflt_op(&mut self, mem_idx: usize) -> Result<(), String> {
+            let f = f64::from_bits(self.mem_stack[mem_idx] as u64);
+            self.stack.push(Value::Float(f));
             Ok(())
         }
 
         fn pushstr_op(&mut self, mem_idx: usize) -> Result<(), String> {
-            self.stack.push(mem_idx as i64);
-            self.sp = self.stack.len() - 1;
+            self.stack.push(Value::Str(mem_idx));
+            Ok(())
+        }
+
+        fn frame(&mut self) -> &mut Frame {
+            self.frames.last_mut().unwrap()
+        }
+
+        fn load_op(&mut self, op: &Op, idx: usize) -> Result<(), String> {
+            match self.frame().vars.get(idx).copied() {
+                Some(Some(v)) => {
+                    self.stack.push(v);
+                    Ok(())
+                }
+                // Named the way the interpreter names it, when the debug info knows the name
+                _ => {
+                    let vars = &self.debug_info.vars;
+                    match vars.binary_search_by_key(&self.op_start, |v| v.0) {
+                        Ok(i) => Err(format!("Invalid: {} undefined", vars[i].1)),
+                        Err(_) => Err(format!("Invalid {:?}: Variable used before assignment", op)),
+                    }
+                }
+            }
+        }
+
+        fn store_op(&mut self, op: &Op, idx: usize) -> Result<(), String> {
+            let v = self.pop(op)?;
+            let vars = &mut self.frame().vars;
+            if vars.len() <= idx {
+                vars.resize(idx + 1, None);
+            }
+            vars[idx] = Some(v);
+            Ok(())
+        }
+
+        fn call_op(&mut self, op: &Op, target: usize) -> Result<(), String> {
+            if self.frames.len() >= MAX_FRAMES {
+                return Err(format!("Invalid {:?}: Call stack overflow", op));
+            }
+            self.frames.push(Frame {
+                ret: self.ip,
+                vars: vec![],
+            });
+            self.ip = target;
+            Ok(())
+        }
+
+        fn ret_op(&mut self, op: &Op) -> Result<(), String> {
+            if self.frames.len() < 2 {
+                return Err(format!("Invalid {:?}: Not inside a word", op));
+            }
+            let frame = self.frames.pop().unwrap();
+            self.ip = frame.ret;
+            Ok(())
+        }
+
+        fn dup_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let sp = self.top(op, 1)?;
+            self.stack.push(self.stack[sp]);
+            Ok(())
+        }
+
+        fn drop_op(&mut self, op: &Builtin) -> Result<(), String> {
+            self.pop(op)?;
+            Ok(())
+        }
+
+        fn swap_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let sp = self.top(op, 2)?;
+            self.stack.swap(sp - 1, sp);
+            Ok(())
+        }
+
+        fn nip_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let sp = self.top(op, 2)?;
+            self.stack.remove(sp - 1);
+            Ok(())
+        }
+
+        fn rot_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let sp = self.top(op, 3)?;
+            let tmp = self.stack.remove(sp - 2);
+            self.stack.push(tmp);
+            Ok(())
+        }
+
+        fn over_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let sp = self.top(op, 2)?;
+            self.stack.push(self.stack[sp - 1]);
             Ok(())
         }
 
-        fn swap_op(&mut self) -> Result<(), String> {
-            let tmp = self.stack[self.sp - 1];
-            self.stack[self.sp - 1] = self.stack[self.sp];
-            self.stack[self.sp] = tmp;
+        fn twodup_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let sp = self.top(op, 2)?;
+            self.stack.extend_from_within(sp - 1..);
+            Ok(())
+        }
 
+        fn twodrop_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let sp = self.top(op, 2)?;
+            self.stack.truncate(sp - 1);
             Ok(())
         }
 
-        fn rot_op(&mut self) -> Result<(), String> {
-            let tmp = self.stack[self.sp - 2];
-            self.stack[self.sp - 2] = self.stack[self.sp - 1];
-            self.stack[self.sp - 1] = self.stack[self.sp];
-            self.stack[self.sp] = tmp;
+        fn tuck_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let sp = self.top(op, 2)?;
+            self.stack.insert(sp - 1, self.stack[sp]);
+            Ok(())
+        }
 
+        fn pick_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let n = self.pop_int(op)?;
+            if n < 0 || n as usize >= self.stack.len() {
+                return Err(format!(
+                    "Invalid {:?}: Not enough values on the stack to pick {}",
+                    op, n
+                ));
+            }
+            self.stack
+                .push(self.stack[self.stack.len() - 1 - n as usize]);
             Ok(())
         }
 
-        fn printi_op(&mut self) -> Result<(), String> {
-            let t = self.stack.pop().unwrap();
-            println!("{}", t);
+        fn depth_op(&mut self) -> Result<(), String> {
+            self.stack.push(Value::Int(self.stack.len() as i64));
             Ok(())
         }
 
-        fn prints_op(&mut self) -> Result<(), String> {
-            let mut t = self.stack.pop().unwrap() as usize;
+        fn string_at(&self, mut t: usize) -> String {
+            let mut s = String::new();
             while self.mem_stack[t] != 0 {
-                let c = self.mem_stack[t as usize] as u8;
-                print!("{}", c as char);
+                if let Some(c) = char::from_u32(self.mem_stack[t] as u32) {
+                    s.push(c);
+                }
                 t += 1;
             }
-            println!("");
+            s
+        }
+
+        fn format_value(&self, v: &Value) -> String {
+            match v {
+                Value::Int(i) => i.to_string(),
+                // Same as printf's %f
+                Value::Float(f) => format!("{:.6}", f),
+                Value::Str(t) => self.string_at(*t),
+            }
+        }
+
+        fn print_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let v = match op {
+                Builtin::Peek => {
+                    let sp = self.top(op, 1)?;
+                    self.stack[sp]
+                }
+                _ => self.pop(op)?,
+            };
+            print!("{} ", self.format_value(&v));
+            Ok(())
+        }
+
+        fn printc_op(&mut self, op: &Builtin) -> Result<(), String> {
+            let c = self.pop_int(op)?;
+            match char::from_u32(c as u32) {
+                Some(c) => print!("{}", c),
+                None => return Err(format!("Invalid {:?}: {} is not a character", op, c)),
+            }
+            Ok(())
+        }
+
+        fn dbg_op(&mut self) -> Result<(), String> {
+            print!("Debug: ");
+            for i in &self.stack {
+                print!("{} ", self.format_value(i));
+            }
+            println!();
             Ok(())
         }
 
-        pub fn interpret(&mut self) -> Result<(), String> {
+        pub fn interpret(&mut self) -> Result<(), Diagnostic> {
+            match self.run() {
+                Ok(()) => Ok(()),
+                Err(e) => Err(self.locate(e)),
+            }
+        }
+
+        fn run(&mut self) -> Result<(), String> {
             self.ip = 0;
-            while self.ip < self.bytes.len() {
-                match self.bytes[self.ip] {
+            self.frames = vec![Frame {
+                ret: self.code.len(),
+                vars: vec![],
+            }];
+            while self.ip < self.code.len() {
+                self.op_start = self.ip;
+                let op = decode(&self.code, &mut self.ip)?;
+                match op {
                     Op::NOOP => Ok(()),
-                    Op::PUSHNUM(n) => self.pushnum_op(Some(n)),
+                    Op::PUSHNUM(n) => self.pushnum_op(n),
                     Op::PUSHSTR(n) => self.pushstr_op(n),
-                    Op::DUP => self.pushnum_op(None),
-                    Op::SWAP => self.swap_op(),
-                    Op::ROT => self.rot_op(),
-                    Op::ADD => self.math_op(&self.bytes[self.ip].clone()),
-                    Op::SUB => self.math_op(&self.bytes[self.ip].clone()),
-                    Op::MUL => self.math_op(&self.bytes[self.ip].clone()),
-                    Op::DIV => self.math_op(&self.bytes[self.ip].clone()),
-                    Op::PRINTI => self.printi_op(),
-                    Op::PRINTS => self.prints_op(),
-                    Op::DBG => {
-                        for i in &self.stack {
-                            print!("{} ", i);
-                        }
-                        println!("");
-                        Ok(())
-                    }
-                    Op::HALT => Ok(()),
-                    _ => {
+                    Op::PUSHFLT(n) => self.pushflt_op(n),
+                    Op::DUP => self.dup_op(&Builtin::Dup),
+                    Op::DROP => self.drop_op(&Builtin::Drop),
+                    Op::SWAP => self.swap_op(&Builtin::Swap),
+                    Op::NIP => self.nip_op(&Builtin::Nip),
+                    Op::ROT => self.rot_op(&Builtin::Rot),
+                    Op::OVER => self.over_op(&Builtin::Over),
+                    Op::TWODUP => self.twodup_op(&Builtin::TwoDup),
+                    Op::TWODROP => self.twodrop_op(&Builtin::TwoDrop),
+                    Op::TUCK => self.tuck_op(&Builtin::Tuck),
+                    Op::PICK => self.pick_op(&Builtin::Pick),
+                    Op::DEPTH => self.depth_op(),
+                    Op::ADD => self.prim_op(&Builtin::Add, Prim::Add),
+                    Op::SUB => self.prim_op(&Builtin::Sub, Prim::Sub),
+                    Op::MUL => self.prim_op(&Builtin::Mul, Prim::Mul),
+                    Op::DIV => self.prim_op(&Builtin::Div, Prim::Div),
+                    Op::MOD => self.prim_op(&Builtin::Mod, Prim::Mod),
+                    Op::ITOF => self.prim_op(&Builtin::IntToFloat, Prim::IntToFloat),
+                    Op::FTOI => self.prim_op(&Builtin::FloatToInt, Prim::FloatToInt),
+                    Op::EQ => self.prim_op(&Builtin::Eq, Prim::Eq),
+                    Op::NE => self.prim_op(&Builtin::Ne, Prim::Ne),
+                    Op::LT => self.prim_op(&Builtin::Lt, Prim::Lt),
+                    Op::LE => self.prim_op(&Builtin::Le, Prim::Le),
+                    Op::GT => self.prim_op(&Builtin::Gt, Prim::Gt),
+                    Op::GE => self.prim_op(&Builtin::Ge, Prim::Ge),
+                    Op::AND => self.prim_op(&Builtin::And, Prim::And),
+                    Op::OR => self.prim_op(&Builtin::Or, Prim::Or),
+                    Op::NOT => self.prim_op(&Builtin::Not, Prim::Not),
+                    Op::JMP(_) | Op::JZ(_) | Op::JNZ(_) => self.jump_op(&op),
+                    Op::LOAD(n) => self.load_op(&op, n),
+                    Op::STORE(n) => self.store_op(&op, n),
+                    Op::CALL(n) => self.call_op(&op, n),
+                    Op::RET => self.ret_op(&op),
+                    Op::PRINT => self.print_op(&Builtin::Print),
+                    Op::PEEK => self.print_op(&Builtin::Peek),
+                    Op::PRINTC => self.printc_op(&Builtin::PrintChar),
+                    Op::DBG => self.dbg_op(),
+                    Op::HALT => return Ok(()),
+                    Op::CONST(_) => {
                         return Err(format!(
                             "{}:{}:{}: Unknown instruction: {:?}",
                             file!(),
                             line!(),
                             column!(),
-                            self.bytes[self.ip]
+                            op
                         ))
                     }
                 }?;
-                self.ip += 1;
             }
             Ok(())
         }
 
         pub fn disassemble(&mut self) -> Result<i32, String> {
             println!("DISSASSEMBLE:");
-            println!("{:?}", self.bytes);
             self.ip = 0;
-            while self.ip < self.bytes.len() {
-                match self.bytes[self.ip] {
-                    Op::NOOP => {}
+            while self.ip < self.code.len() {
+                print!("{:04} ", self.ip);
+                let op = decode(&self.code, &mut self.ip)?;
+                match op {
+                    Op::NOOP => println!("NOOP"),
                     Op::PUSHNUM(n) => println!("PUSH {}", self.mem_stack[n]),
-                    Op::PUSHSTR(n) => println!("PUSH {}", n),
-                    // 0x11 => {}
-                    // 0x21 => {}
-                    Op::PRINTI => println!("PRINTI"),
-                    Op::PRINTS => println!("PRINTS"),
-                    // 0x02 => {}
-                    // 0x12 => {}
-                    // 0x03 => {}
+                    Op::PUSHSTR(n) => println!("PUSH {:?}", self.string_at(n)),
+                    Op::PUSHFLT(n) => {
+                        println!("PUSH {:?}", f64::from_bits(self.mem_stack[n] as u64))
+                    }
+                    Op::DROP => println!("DROP"),
+                    Op::DUP => println!("DUP"),
+                    Op::SWAP => println!("SWAP"),
+                    Op::NIP => println!("NIP"),
+                    Op::ROT => println!("ROT"),
+                    Op::OVER => println!("OVER"),
+                    Op::TWODUP => println!("TWODUP"),
+                    Op::TWODROP => println!("TWODROP"),
+                    Op::TUCK => println!("TUCK"),
+                    Op::PICK => println!("PICK"),
+                    Op::DEPTH => println!("DEPTH"),
                     Op::ADD => println!("ADD"),
                     Op::SUB => println!("SUB"),
                     Op::MUL => println!("MUL"),
                     Op::DIV => println!("DIV"),
+                    Op::MOD => println!("MOD"),
+                    Op::ITOF => println!("ITOF"),
+                    Op::FTOI => println!("FTOI"),
+                    Op::EQ => println!("EQ"),
+                    Op::NE => println!("NE"),
+                    Op::LT => println!("LT"),
+                    Op::LE => println!("LE"),
+                    Op::GT => println!("GT"),
+                    Op::GE => println!("GE"),
+                    Op::AND => println!("AND"),
+                    Op::OR => println!("OR"),
+                    Op::NOT => println!("NOT"),
+                    Op::JMP(n) => println!("JMP {:04}", n),
+                    Op::JZ(n) => println!("JZ {:04}", n),
+                    Op::JNZ(n) => println!("JNZ {:04}", n),
+                    Op::LOAD(n) => println!("LOAD {}", n),
+                    Op::STORE(n) => println!("STORE {}", n),
+                    Op::CALL(n) => println!("CALL {:04}", n),
+                    Op::RET => println!("RET"),
+                    Op::PRINT => println!("PRINT"),
+                    Op::PEEK => println!("PEEK"),
+                    Op::PRINTC => println!("PRINTC"),
                     Op::DBG => println!("DBG"),
                     Op::HALT => println!("HALT"),
-                    _ => {
+                    Op::CONST(_) => {
                         return Err(format!(
                             "{}:{}:{}: Unknown instruction: {:?}",
                             file!(),
                             line!(),
                             column!(),
-                            self.bytes[self.ip]
+                            op
                         ))
                     }
                 }
-                self.ip += 1;
             }
             Ok(0)
         }
     }
 
-    pub fn new(bytes: Vec<Op>, mem_stack: Vec<i64>) -> VM {
+    pub fn new(code: Vec<u8>, mem_stack: Vec<i64>) -> VM {
         VM {
-            bytes,
+            code,
             ip: 0,
-            sp: 0,
+            op_start: 0,
             stack: vec![],
             mem_stack,
+            frames: vec![],
+            debug_info: DebugInfo::default(),
+        }
+    }
+
+    struct Reader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl Reader<'_> {
+        fn take(&mut self, n: usize) -> Result<&[u8], String> {
+            if self.pos + n > self.data.len() {
+                return Err("Invalid .rvm file: unexpected end of file".to_string());
+            }
+            let s = &self.data[self.pos..self.pos + n];
+            self.pos += n;
+            Ok(s)
+        }
+
+        fn u16(&mut self) -> Result<u16, String> {
+            Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+        }
+
+        fn u32(&mut self) -> Result<u32, String> {
+            Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+        }
+
+        fn uleb(&mut self) -> Result<u64, String> {
+            read_uleb(self.data, &mut self.pos)
+        }
+
+        fn sleb(&mut self) -> Result<i64, String> {
+            read_sleb(self.data, &mut self.pos)
+        }
+    }
+
+    // Builds a VM from the contents of a .rvm file written by compiler::Compiler::to_rvm
+    pub fn load(data: &[u8]) -> Result<VM, String> {
+        let mut r = Reader { data, pos: 0 };
+        if r.take(4)? != RVM_MAGIC {
+            return Err("Invalid .rvm file: missing RVM header".to_string());
+        }
+        let version = r.u16()?;
+        if version != RVM_VERSION {
+            return Err(format!(
+                "Invalid .rvm file: unsupported version {} (expected {})",
+                version, RVM_VERSION
+            ));
+        }
+        let flags = r.u16()?;
+
+        // Counts come from the file, so nothing is allocated up front for them; a bad count
+        // runs out of data instead
+        let const_count = r.u32()? as usize;
+        let mut const_pool = vec![];
+        for _ in 0..const_count {
+            const_pool.push(r.sleb()?);
+        }
+
+        let code_len = r.u32()? as usize;
+        let code = r.take(code_len)?.to_vec();
+        verify(&code, &const_pool)?;
+
+        let mut vm = new(code, const_pool);
+        if flags & RVM_FLAG_DEBUG != 0 {
+            let mut files: Vec<Rc<str>> = vec![];
+            for _ in 0..r.u32()? {
+                let len = r.u32()? as usize;
+                files.push(String::from_utf8_lossy(r.take(len)?).into());
+            }
+            let mut spans = vec![];
+            for _ in 0..r.u32()? {
+                let offset = r.uleb()? as usize;
+                let file = match files.get(r.uleb()? as usize) {
+                    Some(f) => f.clone(),
+                    None => return Err("Invalid .rvm file: debug entry without a file".to_string()),
+                };
+                let (start, end) = (r.uleb()? as usize, r.uleb()? as usize);
+                spans.push((offset, Span { file, start, end }));
+            }
+            let mut vars = vec![];
+            for _ in 0..r.u32()? {
+                let offset = r.uleb()? as usize;
+                let len = r.u32()? as usize;
+                vars.push((offset, String::from_utf8_lossy(r.take(len)?).into_owned()));
+            }
+            vm.set_debug(DebugInfo { spans, vars });
+        }
+        Ok(vm)
+    }
+
+    // Checks that every op decodes, references a valid constant and variable slot and jumps to
+    // the start of an op
+    fn verify(code: &[u8], const_pool: &[i64]) -> Result<(), String> {
+        let mut starts = vec![false; code.len() + 1];
+        let mut ops = vec![];
+        let mut pos = 0;
+        while pos < code.len() {
+            starts[pos] = true;
+            ops.push(decode(code, &mut pos)?);
+        }
+        starts[code.len()] = true;
+        // The compiler numbers the slots of a word from 0, each the first time it's stored to,
+        // so no slot is higher than the number of stores
+        let stores = ops.iter().filter(|op| matches!(op, Op::STORE(_))).count();
+        for op in &ops {
+            let bad = match *op {
+                Op::PUSHNUM(n) | Op::PUSHFLT(n) => n >= const_pool.len(),
+                Op::PUSHSTR(n) => n >= const_pool.len() || !const_pool[n..].contains(&0),
+                Op::JMP(n) | Op::JZ(n) | Op::JNZ(n) | Op::CALL(n) => {
+                    n >= starts.len() || !starts[n]
+                }
+                Op::LOAD(n) | Op::STORE(n) => n >= stores,
+                _ => false,
+            };
+            if bad {
+                return Err(format!(
+                    "Invalid .rvm file: operand out of range in {:?}",
+                    op
+                ));
+            }
         }
+        Ok(())
     }
 }
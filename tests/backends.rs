@@ -0,0 +1,61 @@
+use rorth::{
+    compiler::compiler, diagnostic::diagnostic::Diagnostic, interpreter::interpreter, lexer::lexer,
+    parser::parser, vm::vm,
+};
+
+fn run_both(source: &str) -> (Diagnostic, Diagnostic) {
+    let parse = || {
+        let mut l = lexer::new("test.rorth".to_string(), source.to_string());
+        parser::new(l.lex().unwrap()).parse().unwrap()
+    };
+    let interp = interpreter::new().interpret(parse()).unwrap_err();
+
+    let mut c = compiler::new();
+    c.compile(&parse()).unwrap();
+    let (code, debug_info) = c.assemble();
+    let mut vm = vm::new(code, c.const_pool);
+    vm.set_debug(debug_info);
+    (interp, vm.interpret().unwrap_err())
+}
+
+// Both backends report a runtime error with the same message at the same place
+fn same_error(source: &str, message: &str, at: &str) {
+    let (interp, vm) = run_both(source);
+    let start = source.rfind(at).unwrap();
+    for e in [&interp, &vm] {
+        assert_eq!(e.message, message, "in {:?}", source);
+        assert_eq!(e.file, "test.rorth");
+        assert_eq!(e.span, Some(start..start + at.len()), "in {:?}", source);
+    }
+}
+
+#[test]
+fn division_by_zero() {
+    same_error("1 0 /", "Invalid Div: Division by zero", "/");
+    same_error("7 0 mod", "Invalid Mod: Division by zero", "mod");
+}
+
+#[test]
+fn stack_underflow() {
+    // The parser checks stack effects, so only a branch it can't follow runs out of values
+    same_error(
+        "0 if 1 end drop",
+        "Invalid Drop: Not enough values on the stack",
+        "drop",
+    );
+    same_error(
+        "0 if 1 end 2 +",
+        "Invalid Add: Not enough values on the stack",
+        "+",
+    );
+    same_error(
+        "0 if 1 end .",
+        "Invalid Print: Not enough values on the stack",
+        ".",
+    );
+}
+
+#[test]
+fn unassigned_variable() {
+    same_error("0 if 5 x := end x .", "Invalid: x undefined", "x");
+}
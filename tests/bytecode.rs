@@ -0,0 +1,182 @@
+use rorth::{
+    compiler::compiler::{self, Compiler},
+    lexer::lexer,
+    op::op::{decode, encode, read_sleb, read_uleb, write_sleb, write_uleb, Op},
+    parser::parser,
+    vm::vm,
+};
+
+#[test]
+fn uleb_round_trip() {
+    for n in [
+        0,
+        1,
+        127,
+        128,
+        300,
+        u32::MAX as u64,
+        i64::MAX as u64,
+        u64::MAX,
+    ] {
+        let mut out = vec![];
+        write_uleb(&mut out, n);
+        let mut pos = 0;
+        assert_eq!(read_uleb(&out, &mut pos), Ok(n));
+        assert_eq!(pos, out.len(), "{} left bytes unread", n);
+    }
+}
+
+#[test]
+fn sleb_round_trip() {
+    for n in [0, -1, 1, 63, -64, 64, -65, i64::MIN, i64::MAX] {
+        let mut out = vec![];
+        write_sleb(&mut out, n);
+        let mut pos = 0;
+        assert_eq!(read_sleb(&out, &mut pos), Ok(n));
+        assert_eq!(pos, out.len(), "{} left bytes unread", n);
+    }
+}
+
+#[test]
+fn leb_rejects_truncated_input() {
+    let mut out = vec![];
+    write_uleb(&mut out, u64::MAX);
+    out.pop();
+    assert!(read_uleb(&out, &mut 0).is_err());
+    let mut out = vec![];
+    write_sleb(&mut out, i64::MIN);
+    out.pop();
+    assert!(read_sleb(&out, &mut 0).is_err());
+}
+
+// Every op, with jump and call targets that are indices into this list
+fn all_ops() -> Vec<Op> {
+    vec![
+        Op::NOOP,
+        Op::PUSHNUM(0),
+        Op::PUSHNUM(usize::MAX),
+        Op::PUSHSTR(200),
+        Op::PUSHFLT(3),
+        Op::DROP,
+        Op::DUP,
+        Op::SWAP,
+        Op::NIP,
+        Op::ROT,
+        Op::OVER,
+        Op::TWODUP,
+        Op::TWODROP,
+        Op::TUCK,
+        Op::PICK,
+        Op::DEPTH,
+        Op::ADD,
+        Op::SUB,
+        Op::MUL,
+        Op::DIV,
+        Op::MOD,
+        Op::ITOF,
+        Op::FTOI,
+        Op::EQ,
+        Op::NE,
+        Op::LT,
+        Op::LE,
+        Op::GT,
+        Op::GE,
+        Op::AND,
+        Op::OR,
+        Op::NOT,
+        Op::JMP(0),
+        Op::JZ(5),
+        Op::JNZ(44),
+        Op::LOAD(0),
+        Op::STORE(1000),
+        Op::CALL(3),
+        Op::RET,
+        Op::DBG,
+        Op::CONST(i64::MIN),
+        Op::CONST(-1),
+        Op::PRINT,
+        Op::PEEK,
+        Op::PRINTC,
+        Op::HALT,
+    ]
+}
+
+#[test]
+fn ops_round_trip() {
+    let ops = all_ops();
+    let (code, offsets) = encode(&ops);
+    assert_eq!(offsets.len(), ops.len());
+    let mut pos = 0;
+    for (i, op) in ops.iter().enumerate() {
+        assert_eq!(pos, offsets[i], "{:?} starts at the wrong offset", op);
+        // Encoding turns op indices into byte offsets
+        let expected = match *op {
+            Op::JMP(n) => Op::JMP(offsets[n]),
+            Op::JZ(n) => Op::JZ(offsets[n]),
+            Op::JNZ(n) => Op::JNZ(offsets[n]),
+            Op::CALL(n) => Op::CALL(offsets[n]),
+            op => op,
+        };
+        assert_eq!(decode(&code, &mut pos), Ok(expected));
+    }
+    assert_eq!(pos, code.len());
+}
+
+fn compile(source: &str) -> Compiler {
+    let mut l = lexer::new("test.rorth".to_string(), source.to_string());
+    let program = parser::new(l.lex().unwrap()).parse().unwrap();
+    let mut c = compiler::new();
+    c.compile(&program).unwrap();
+    c
+}
+
+// Loads and runs the bytecode, which ends by dividing by zero at the /
+const DIV_BY_ZERO: &str = ": f ( a -- b ) 2 * ; 1.5 \"s\" 3 f 6 = nip nip if 1 0 / end";
+
+#[test]
+fn rvm_round_trip() {
+    let c = compile(DIV_BY_ZERO);
+    let mut vm = vm::load(&c.to_rvm(true)).unwrap();
+    let e = vm.interpret().unwrap_err();
+    assert_eq!(e.message, "Invalid Div: Division by zero");
+    assert_eq!(e.file, "test.rorth");
+    let slash = DIV_BY_ZERO.find('/').unwrap();
+    assert_eq!(e.span, Some(slash..slash + 1));
+}
+
+#[test]
+fn rvm_without_debug_section() {
+    let c = compile(DIV_BY_ZERO);
+    let mut vm = vm::load(&c.to_rvm(false)).unwrap();
+    let e = vm.interpret().unwrap_err();
+    assert_eq!(e.message, "Invalid Div: Division by zero");
+    assert_eq!(e.span, None);
+}
+
+#[test]
+fn rvm_rejects_bad_files() {
+    let rvm = compile(DIV_BY_ZERO).to_rvm(true);
+    for len in 0..rvm.len() {
+        assert!(
+            vm::load(&rvm[..len]).is_err(),
+            "loaded {} of {} bytes",
+            len,
+            rvm.len()
+        );
+    }
+    let mut bad_version = rvm.clone();
+    bad_version[4] ^= 0xff;
+    assert!(vm::load(&bad_version).is_err());
+}
+
+#[test]
+fn rvm_names_unassigned_variables() {
+    let c = compile("0 if 5 x := end x .");
+    let mut vm = vm::load(&c.to_rvm(true)).unwrap();
+    assert_eq!(vm.interpret().unwrap_err().message, "Invalid: x undefined");
+    let mut vm = vm::load(&c.to_rvm(false)).unwrap();
+    assert_eq!(
+        vm.interpret().unwrap_err().message,
+        "Invalid LOAD(0): Variable used before assignment"
+    );
+}
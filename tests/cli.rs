@@ -0,0 +1,213 @@
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+// Exit codes of rorth
+const OK: i32 = 0;
+const ERRORS: i32 = 1;
+const USAGE: i32 = 2;
+const RUNTIME: i32 = 3;
+const IO: i32 = 4;
+
+// A directory for one test to write files to and run rorth in
+fn dir(name: &str) -> PathBuf {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+        .join("cli")
+        .join(name);
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    root
+}
+
+struct Output {
+    code: i32,
+    stdout: String,
+    stderr: String,
+}
+
+fn rorth_with(dir: &PathBuf, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rorth"))
+        .args(args)
+        .current_dir(dir)
+        // qbe and cc are never found, so builds fail the same way everywhere
+        .env("PATH", "")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    Output {
+        code: out.status.code().unwrap(),
+        stdout: String::from_utf8(out.stdout).unwrap(),
+        stderr: String::from_utf8(out.stderr).unwrap(),
+    }
+}
+
+fn rorth(dir: &PathBuf, args: &[&str]) -> Output {
+    rorth_with(dir, args, "")
+}
+
+fn status(dir: &PathBuf, args: &[&str]) -> i32 {
+    rorth(dir, args).code
+}
+
+#[test]
+fn usage() {
+    let d = dir("usage");
+    fs::write(d.join("ok.rorth"), "1 .").unwrap();
+    let out = rorth(&d, &[]);
+    assert_eq!(out.code, USAGE);
+    assert!(out.stderr.starts_with("usage: rorth"), "{}", out.stderr);
+    let out = rorth(&d, &["--help"]);
+    assert_eq!(out.code, OK);
+    assert!(out.stdout.starts_with("usage: rorth"), "{}", out.stdout);
+
+    for args in [
+        &["frobnicate", "ok.rorth"][..],
+        &["run"],
+        &["run", "ok.rorth", "ok.rorth"],
+        &["run", "--nope", "ok.rorth"],
+        &["run", "--backend", "jvm", "ok.rorth"],
+        &["run", "--backend"],
+        &["run", "-o", "x", "ok.rorth"],
+        &["run", "--ast", "ok.rorth"],
+        &["emit", "ok.rorth"],
+        &["emit", "--ast", "--ssa", "ok.rorth"],
+        &["check", "--max-errors", "many", "ok.rorth"],
+        &["repl", "ok.rorth"],
+        &["build", "--backend", "interp", "ok.rorth"],
+    ] {
+        let out = rorth(&d, args);
+        assert_eq!(out.code, USAGE, "{:?}: {}", args, out.stderr);
+        assert!(out.stderr.contains("error:"), "{:?}: {}", args, out.stderr);
+    }
+}
+
+#[test]
+fn run_and_check() {
+    let d = dir("run_and_check");
+    fs::write(d.join("ok.rorth"), ": sq ( a -- b ) dup * ; 7 sq .").unwrap();
+    for backend in ["interp", "vm"] {
+        let out = rorth(&d, &["run", "--backend", backend, "ok.rorth"]);
+        assert_eq!(out.code, OK, "{}", out.stderr);
+        assert_eq!(out.stdout.trim(), "49");
+    }
+    assert_eq!(rorth(&d, &["run", "ok.rorth"]).stdout.trim(), "49");
+    let out = rorth(&d, &["check", "--verbose", "ok.rorth"]);
+    assert_eq!(out.code, OK);
+    assert_eq!(out.stdout, "");
+    assert!(
+        out.stderr.contains("rorth: ok.rorth is ok"),
+        "{}",
+        out.stderr
+    );
+}
+
+#[test]
+fn program_errors() {
+    let d = dir("program_errors");
+    fs::write(d.join("undefined.rorth"), "foo").unwrap();
+    fs::write(d.join("types.rorth"), "\"a\" 1 +").unwrap();
+    for file in ["undefined.rorth", "types.rorth"] {
+        for cmd in ["check", "run"] {
+            let out = rorth(&d, &[cmd, file]);
+            assert_eq!(out.code, ERRORS, "{} {}", cmd, file);
+            assert!(
+                out.stderr.contains(&format!("{}:1:", file)),
+                "{}",
+                out.stderr
+            );
+        }
+    }
+    fs::write(d.join("bad.rvm"), "not bytecode").unwrap();
+    assert_eq!(status(&d, &["disasm", "bad.rvm"]), ERRORS);
+    assert_eq!(status(&d, &["run", "bad.rvm"]), ERRORS);
+}
+
+#[test]
+fn runtime_errors() {
+    let d = dir("runtime_errors");
+    fs::write(d.join("div.rorth"), "1 0 /").unwrap();
+    for backend in ["interp", "vm"] {
+        let out = rorth(&d, &["run", "--backend", backend, "div.rorth"]);
+        assert_eq!(out.code, RUNTIME, "{}", backend);
+        assert!(out.stderr.contains("Division by zero"), "{}", out.stderr);
+    }
+}
+
+#[test]
+fn io_errors() {
+    let d = dir("io_errors");
+    for args in [
+        &["run", "missing.rorth"][..],
+        &["check", "missing.rorth"],
+        &["disasm", "missing.rvm"],
+    ] {
+        let out = rorth(&d, args);
+        assert_eq!(out.code, IO, "{:?}", args);
+        assert!(
+            out.stderr.contains("Error reading missing"),
+            "{}",
+            out.stderr
+        );
+    }
+    // There's no qbe on the empty PATH
+    fs::write(d.join("ok.rorth"), "1 .").unwrap();
+    for args in [
+        &["build", "ok.rorth"][..],
+        &["run", "--backend", "qbe", "ok.rorth"],
+    ] {
+        let out = rorth(&d, args);
+        assert_eq!(out.code, IO, "{:?}", args);
+        assert!(out.stderr.contains("Failed to run qbe"), "{}", out.stderr);
+    }
+}
+
+#[test]
+fn bytecode_files() {
+    let d = dir("bytecode_files");
+    fs::write(d.join("ok.rorth"), "2 3 + .").unwrap();
+    assert_eq!(
+        status(
+            &d,
+            &["build", "--backend", "vm", "-o", "ok.rvm", "ok.rorth"]
+        ),
+        OK
+    );
+    let out = rorth(&d, &["run", "ok.rvm"]);
+    assert_eq!(out.code, OK, "{}", out.stderr);
+    assert_eq!(out.stdout.trim(), "5");
+    let out = rorth(&d, &["disasm", "ok.rvm"]);
+    assert_eq!(out.code, OK, "{}", out.stderr);
+    assert!(out.stdout.contains("ADD"), "{}", out.stdout);
+    assert_eq!(status(&d, &["run", "--backend", "interp", "ok.rvm"]), USAGE);
+}
+
+#[test]
+fn emit() {
+    let d = dir("emit");
+    fs::write(d.join("ok.rorth"), "2 3 + .").unwrap();
+    for flag in ["--tokens", "--ast", "--ssa", "--bytecode"] {
+        let out = rorth(&d, &["emit", flag, "ok.rorth"]);
+        assert_eq!(out.code, OK, "{}: {}", flag, out.stderr);
+        assert!(!out.stdout.is_empty(), "{}", flag);
+    }
+}
+
+#[test]
+fn repl() {
+    let d = dir("repl");
+    let out = rorth_with(&d, &["repl"], "1 2 +\n");
+    assert_eq!(out.code, OK, "{}", out.stderr);
+    assert!(out.stdout.contains("<1> 3"), "{}", out.stdout);
+}
@@ -0,0 +1,79 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn examples() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "rorth"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty());
+    files
+}
+
+fn run(backend: &str, file: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rorth"))
+        .args(["run", "--backend", backend])
+        .arg(file)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn interpreter_and_vm_agree() {
+    for file in examples() {
+        let interp = run("interp", &file);
+        let vm = run("vm", &file);
+        assert!(interp.status.success(), "{} failed", file.display());
+        assert_eq!(
+            String::from_utf8_lossy(&interp.stdout),
+            String::from_utf8_lossy(&vm.stdout),
+            "{} prints something else on the vm",
+            file.display()
+        );
+        assert_eq!(interp.status.code(), vm.status.code());
+    }
+}
+
+// Whether a tool can be started from PATH
+fn found(tool: &str) -> bool {
+    Command::new(tool).arg("--version").output().is_ok()
+}
+
+#[test]
+fn native_binaries_agree() {
+    if !found("qbe") || !found("gcc") {
+        eprintln!("skipping: qbe or gcc not found");
+        return;
+    }
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("examples");
+    for file in examples() {
+        let bin = out_dir.join(file.file_stem().unwrap());
+        let build = Command::new(env!("CARGO_BIN_EXE_rorth"))
+            .args(["build", "--backend", "qbe", "-o"])
+            .arg(&bin)
+            .arg(&file)
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .output()
+            .unwrap();
+        assert!(
+            build.status.success(),
+            "{} doesn't build: {}",
+            file.display(),
+            String::from_utf8_lossy(&build.stderr)
+        );
+        let native = Command::new(&bin).output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&run("interp", &file).stdout),
+            String::from_utf8_lossy(&native.stdout),
+            "{} prints something else as a binary",
+            file.display()
+        );
+    }
+}
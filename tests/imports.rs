@@ -0,0 +1,123 @@
+use std::{fs, path::PathBuf, process::Command};
+
+// An empty directory for one test, with files written into it by path
+struct Dir(PathBuf);
+
+impl Dir {
+    fn new(name: &str) -> Dir {
+        let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+            .join("imports")
+            .join(name);
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        Dir(root)
+    }
+
+    fn write(&self, path: &str, source: &str) {
+        let path = self.0.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, source).unwrap();
+    }
+
+    fn remove(&self, path: &str) {
+        fs::remove_file(self.0.join(path)).unwrap();
+    }
+
+    // Runs rorth in the directory with RORTH_PATH set to rorth_path, returning the exit code,
+    // stdout and stderr
+    fn run(&self, args: &[&str], rorth_path: &[&str]) -> (i32, String, String) {
+        let paths: Vec<PathBuf> = rorth_path.iter().map(|p| self.0.join(p)).collect();
+        let out = Command::new(env!("CARGO_BIN_EXE_rorth"))
+            .args(args)
+            .current_dir(&self.0)
+            .env("RORTH_PATH", std::env::join_paths(paths).unwrap())
+            .output()
+            .unwrap();
+        (
+            out.status.code().unwrap(),
+            String::from_utf8(out.stdout).unwrap(),
+            String::from_utf8(out.stderr).unwrap(),
+        )
+    }
+}
+
+fn stdout(res: (i32, String, String)) -> String {
+    assert_eq!(res.0, 0, "{}", res.2);
+    res.1.trim().to_string()
+}
+
+#[test]
+fn search_order() {
+    let d = Dir::new("search_order");
+    d.write("src/main.rorth", "-- lib ; which");
+    d.write("src/lib.rorth", ": which 1 . ;");
+    d.write("inc/lib.rorth", ": which 2 . ;");
+    d.write("path/lib.rorth", ": which 3 . ;");
+    d.write("std/lib.rorth", ": which 4 . ;");
+    let run = || d.run(&["run", "-I", "inc", "src/main.rorth"], &["empty", "path"]);
+
+    assert_eq!(stdout(run()), "1");
+    d.remove("src/lib.rorth");
+    assert_eq!(stdout(run()), "2");
+    d.remove("inc/lib.rorth");
+    assert_eq!(stdout(run()), "3");
+    d.remove("path/lib.rorth");
+    assert_eq!(stdout(run()), "4");
+    d.remove("std/lib.rorth");
+    let (code, _, err) = run();
+    assert_eq!(code, 1);
+    assert!(
+        err.contains("Invalid import lib: lib.rorth not found in src, inc, "),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn nested_names() {
+    let d = Dir::new("nested_names");
+    d.write("main.rorth", "-- collections/list ; which");
+    d.write("std/collections/list.rorth", ": which 5 . ;");
+    assert_eq!(stdout(d.run(&["run", "main.rorth"], &[])), "5");
+}
+
+#[test]
+fn imported_once() {
+    let d = Dir::new("imported_once");
+    // lib/c.rorth is reached as lib/c from here and as c through -I lib and from a.rorth
+    d.write("main.rorth", "-- lib/c c a ; 2 .");
+    d.write("a.rorth", "-- lib/c ; 3 .");
+    d.write("lib/c.rorth", ": cw 1 . ; cw");
+    assert_eq!(
+        stdout(d.run(&["run", "-I", "lib", "main.rorth"], &[])),
+        "1 3 2"
+    );
+}
+
+#[test]
+fn cycles() {
+    let d = Dir::new("cycles");
+    d.write("main.rorth", "-- a ; 1 .");
+    d.write("a.rorth", "-- b ; : fa 1 ;");
+    d.write("b.rorth", "-- c ; : fb 1 ;");
+    d.write("c.rorth", "-- a ; : fc 1 ;");
+    let (code, _, err) = d.run(&["check", "main.rorth"], &[]);
+    assert_eq!(code, 1);
+    assert!(
+        err.contains("Import cycle: ./a.rorth -> ./b.rorth -> ./c.rorth -> ./a.rorth"),
+        "{}",
+        err
+    );
+    // It's reported at the import that closes the cycle
+    assert!(err.contains("./c.rorth:1:4: error"), "{}", err);
+
+    // A file importing itself
+    d.write("main.rorth", "-- main ;");
+    let (code, _, err) = d.run(&["check", "main.rorth"], &[]);
+    assert_eq!(code, 1);
+    assert!(
+        err.contains("Import cycle: main.rorth -> ./main.rorth"),
+        "{}",
+        err
+    );
+}
@@ -0,0 +1,185 @@
+use rorth::lexer::lexer::{self, TokenType};
+
+// Token types of source, without the EOF at the end, or the messages of its errors
+fn lex(source: &str) -> Result<Vec<TokenType>, Vec<String>> {
+    let mut l = lexer::new("test.rorth".to_string(), source.to_string());
+    match l.lex() {
+        Ok(mut tokens) => {
+            tokens.pop();
+            Ok(tokens.into_iter().map(|t| t.tok_type).collect())
+        }
+        Err(errors) => Err(errors.into_iter().map(|e| e.message).collect()),
+    }
+}
+
+fn int(source: &str) -> i64 {
+    match lex(source).as_deref() {
+        Ok([TokenType::INT(n)]) => *n,
+        other => panic!("{} lexed to {:?}", source, other),
+    }
+}
+
+#[test]
+fn decimal() {
+    assert_eq!(int("0"), 0);
+    assert_eq!(int("42"), 42);
+    assert_eq!(int("-42"), -42);
+    assert_eq!(int("1_000_000"), 1_000_000);
+    assert_eq!(int("-1_000"), -1000);
+}
+
+#[test]
+fn hex_and_binary() {
+    assert_eq!(int("0xff"), 255);
+    assert_eq!(int("0XFF"), 255);
+    assert_eq!(int("0xDead_Beef"), 0xdead_beef);
+    assert_eq!(int("-0x10"), -16);
+    assert_eq!(int("0b101"), 5);
+    assert_eq!(int("0B1111_0000"), 0xf0);
+    assert_eq!(int("-0b1"), -1);
+}
+
+#[test]
+fn limits() {
+    assert_eq!(int("9223372036854775807"), i64::MAX);
+    assert_eq!(int("-9223372036854775808"), i64::MIN);
+    assert_eq!(int("0x7fff_ffff_ffff_ffff"), i64::MAX);
+    assert_eq!(
+        lex("9223372036854775808"),
+        Err(vec![
+            "Invalid number: 9223372036854775808 doesn't fit in 64 bits".to_string()
+        ])
+    );
+    assert_eq!(
+        lex("-9223372036854775809"),
+        Err(vec![
+            "Invalid number: -9223372036854775809 doesn't fit in 64 bits".to_string()
+        ])
+    );
+    assert_eq!(
+        lex("0x8000000000000000"),
+        Err(vec![
+            "Invalid number: 0x8000000000000000 doesn't fit in 64 bits".to_string()
+        ])
+    );
+}
+
+#[test]
+fn floats() {
+    assert_eq!(
+        lex("1.5 -0.25"),
+        Ok(vec![TokenType::FLOAT(1.5), TokenType::FLOAT(-0.25)])
+    );
+}
+
+#[test]
+fn malformed_numbers() {
+    for word in [
+        "0x", "0xFG", "0b102", "0b", "1e5", "12abc", "1.", "1.5.2", "-1x", "0x_",
+    ] {
+        assert_eq!(
+            lex(word),
+            Err(vec![format!("Invalid numeric literal: {}", word)]),
+            "{}",
+            word
+        );
+    }
+}
+
+#[test]
+fn words_that_look_like_numbers() {
+    assert_eq!(
+        lex("2dup 2drop"),
+        Ok(vec![TokenType::TWODUP, TokenType::TWODROP])
+    );
+    assert_eq!(lex("-"), Ok(vec![TokenType::MINUS]));
+    assert_eq!(
+        lex("x1 -x _1"),
+        Ok(vec![
+            TokenType::IDENT("x1".to_string()),
+            TokenType::IDENT("-x".to_string()),
+            TokenType::IDENT("_1".to_string()),
+        ])
+    );
+}
+
+#[test]
+fn defined_words_that_look_like_numbers() {
+    let ident = |s: &str| TokenType::IDENT(s.to_string());
+    assert_eq!(
+        lex(": 2x 2 * ; : +1 1 + ; 3 2x +1"),
+        Ok(vec![
+            TokenType::COLON,
+            ident("2x"),
+            TokenType::INT(2),
+            TokenType::ASTERISK,
+            TokenType::SEMICOLON,
+            TokenType::COLON,
+            ident("+1"),
+            TokenType::INT(1),
+            TokenType::PLUS,
+            TokenType::SEMICOLON,
+            TokenType::INT(3),
+            ident("2x"),
+            ident("+1"),
+        ])
+    );
+    // Used before it's defined, it's still a malformed number
+    assert_eq!(
+        lex("2x : 2x ;"),
+        Err(vec!["Invalid numeric literal: 2x".to_string()])
+    );
+}
+
+fn string(source: &str) -> String {
+    match lex(source).as_deref() {
+        Ok([TokenType::STR(s)]) => s.clone(),
+        other => panic!("{} lexed to {:?}", source, other),
+    }
+}
+
+#[test]
+fn escapes() {
+    assert_eq!(string(r#""a\nb""#), "a\nb");
+    assert_eq!(string(r#""\t""#), "\t");
+    assert_eq!(string(r#""a\\b""#), "a\\b");
+    assert_eq!(string(r#""say \"hi\"""#), "say \"hi\"");
+    assert_eq!(string(r#""\x41\x7f\x00""#), "A\x7f\0");
+    assert_eq!(string(r#""\0""#), "\0");
+    assert_eq!(string("\"\""), "");
+}
+
+#[test]
+fn invalid_escapes() {
+    assert_eq!(
+        lex(r#""\x80""#),
+        Err(vec![
+            "Invalid escape \\x80: expected two hex digits from 00 to 7f".to_string()
+        ])
+    );
+    assert_eq!(
+        lex(r#""\xff""#),
+        Err(vec![
+            "Invalid escape \\xff: expected two hex digits from 00 to 7f".to_string()
+        ])
+    );
+    assert_eq!(
+        lex(r#""\xg1""#),
+        Err(vec![
+            "Invalid escape \\xg1: expected two hex digits from 00 to 7f".to_string()
+        ])
+    );
+    assert_eq!(lex(r#""\q""#), Err(vec!["Invalid escape \\q".to_string()]));
+}
+
+#[test]
+fn unterminated_strings() {
+    let unterminated = Err(vec!["String without closing \"".to_string()]);
+    assert_eq!(lex("\"abc"), unterminated);
+    assert_eq!(lex("\"abc\\"), unterminated);
+    assert_eq!(lex("\"abc\\\""), unterminated);
+    // The error starts at the opening quote
+    let mut l = lexer::new("test.rorth".to_string(), "1 \"abc".to_string());
+    let errors = l.lex().unwrap_err();
+    assert_eq!(errors[0].span.as_ref().map(|s| s.start), Some(2));
+}
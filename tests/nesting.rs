@@ -0,0 +1,157 @@
+use std::{collections::HashSet, thread};
+
+use rorth::{
+    ast::ast::Program,
+    diagnostic::diagnostic::Diagnostic,
+    lexer::lexer,
+    parser::parser::{self, MAX_DEPTH},
+    qbe_backend::qbe_backend,
+};
+
+fn parse(source: &str) -> Result<Program, Vec<Diagnostic>> {
+    let mut l = lexer::new("test.rorth".to_string(), source.to_string());
+    let tokens = l.lex()?;
+    parser::new(tokens).parse()
+}
+
+fn messages(source: &str) -> Vec<String> {
+    match parse(source) {
+        Ok(_) => vec![],
+        Err(errors) => errors.into_iter().map(|e| e.message).collect(),
+    }
+}
+
+// Runs f with the 8 MiB stack rorth's main thread gets; test threads get less
+fn with_main_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(f)
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+// Checks that every label is defined once per function, that each if and loop defines its
+// labels in order, closing the innermost one first, and that every jump goes to a label
+fn check_labels(ssa: &str) {
+    let mut open: Vec<(String, String)> = vec![];
+    let mut defined: HashSet<String> = HashSet::new();
+    let mut targets: Vec<String> = vec![];
+    for line in ssa.lines() {
+        if line == "}" {
+            assert!(open.is_empty(), "blocks left open: {:?}", open);
+            for t in targets.drain(..) {
+                assert!(defined.contains(&t), "jump to undefined label @{}", t);
+            }
+            defined.clear();
+            continue;
+        }
+        let line = line.trim();
+        if line.starts_with("jmp") || line.starts_with("jnz") {
+            let words = line.split([' ', ',']).filter(|w| w.starts_with('@'));
+            targets.extend(words.map(|w| w[1..].to_string()));
+            continue;
+        }
+        let label = match line.strip_prefix('@') {
+            Some(l) => l,
+            None => continue,
+        };
+        assert!(
+            defined.insert(label.to_string()),
+            "@{} defined twice",
+            label
+        );
+        let (kind, id) = match label.rsplit_once('_') {
+            Some((k, id)) => (k, id.to_string()),
+            None => continue,
+        };
+        let top = open.last().cloned();
+        match kind {
+            "if" | "loop" => open.push((kind.to_string(), id)),
+            "else" => assert_eq!(top, Some(("if".to_string(), id)), "@{}", label),
+            "body" => assert_eq!(top, Some(("loop".to_string(), id)), "@{}", label),
+            "end_if" => assert_eq!(open.pop(), Some(("if".to_string(), id)), "@{}", label),
+            "end_loop" => assert_eq!(open.pop(), Some(("loop".to_string(), id)), "@{}", label),
+            _ => {}
+        }
+    }
+}
+
+fn compile(source: &str) -> String {
+    let program = parse(source).expect("program should parse");
+    qbe_backend::new()
+        .compile(&program)
+        .expect("program should compile")
+}
+
+// depth blocks inside each other, alternating between if ... else ... end and loops
+fn nested(depth: usize) -> String {
+    let mut s = "1 ".to_string();
+    for i in 0..depth {
+        s.push_str(if i % 2 == 0 {
+            "1 if "
+        } else {
+            "begin 0 while "
+        });
+    }
+    s.push_str("2 .");
+    for i in (0..depth).rev() {
+        s.push_str(if i % 2 == 0 {
+            " else 3 . end"
+        } else {
+            " repeat"
+        });
+    }
+    s
+}
+
+#[test]
+fn labels_pair_in_nested_blocks() {
+    check_labels(&compile(
+        "1 if 1 if 2 . else 3 . end begin 0 while 1 if 4 . end repeat else 5 . end",
+    ));
+    check_labels(&compile(
+        ": f ( n -- ) n := begin n 0 > nip nip while \
+         1 if 1 . else begin 0 while 2 . repeat end n 1 - n := repeat ; 3 f",
+    ));
+}
+
+#[test]
+fn labels_pair_at_max_depth() {
+    let ssa = with_main_stack(|| compile(&nested(MAX_DEPTH)));
+    check_labels(&ssa);
+}
+
+#[test]
+fn too_deep_is_an_error() {
+    let errors = with_main_stack(|| messages(&nested(MAX_DEPTH + 1)));
+    assert!(
+        errors.contains(&"Blocks nested too deeply".to_string()),
+        "{:?}",
+        errors
+    );
+}
+
+#[test]
+fn mismatched_ends() {
+    assert_eq!(
+        messages("1 if 2 repeat"),
+        [
+            "REPEAT can't close the IF at 1:3; expected ELSE or END",
+            "IF without closing END",
+        ]
+    );
+    assert_eq!(
+        messages("begin 1 while 2 end"),
+        [
+            "END can't close the WHILE at 1:9; expected REPEAT",
+            "WHILE without closing REPEAT",
+        ]
+    );
+    assert_eq!(
+        messages(": f 1 if 2 ; 3"),
+        ["SEMICOLON can't close the IF at 1:7; expected ELSE or END"]
+    );
+    assert_eq!(messages("end"), ["END without matching block"]);
+    assert_eq!(messages("1 if 2"), ["IF without closing END"]);
+}
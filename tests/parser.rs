@@ -0,0 +1,119 @@
+use std::{fs, path::PathBuf, process::Command};
+
+use rorth::{lexer::lexer, parser::parser};
+
+// Row, column and message of every error in source
+fn errors(source: &str) -> Vec<(usize, usize, String)> {
+    let mut l = lexer::new("test.rorth".to_string(), source.to_string());
+    let tokens = l.lex().expect("source should lex");
+    match parser::new(tokens).parse() {
+        Ok(_) => vec![],
+        Err(errors) => errors
+            .into_iter()
+            .map(|e| {
+                let start = e.span.expect("parser errors have spans").start;
+                let row = source[..start].matches('\n').count() + 1;
+                let col = start - source[..start].rfind('\n').map_or(0, |i| i + 1) + 1;
+                (row, col, e.message)
+            })
+            .collect(),
+    }
+}
+
+fn err(row: usize, col: usize, message: &str) -> (usize, usize, String) {
+    (row, col, message.to_string())
+}
+
+#[test]
+fn every_error_in_a_file() {
+    assert_eq!(
+        errors("foo\n: f 1 bar ;\nbaz\n1 if 2"),
+        [
+            err(1, 1, "Invalid: foo undefined"),
+            err(2, 7, "Invalid: bar undefined"),
+            err(3, 1, "Invalid: baz undefined"),
+            err(4, 3, "IF without closing END"),
+        ]
+    );
+}
+
+#[test]
+fn stray_closers_on_one_line() {
+    assert_eq!(
+        errors("end end ; 5 ."),
+        [
+            err(1, 1, "END without matching block"),
+            err(1, 5, "END without matching block"),
+            err(1, 9, "; without matching :"),
+        ]
+    );
+    assert_eq!(
+        errors("1 if 2 repeat 3 end end"),
+        [
+            err(
+                1,
+                8,
+                "REPEAT can't close the IF at 1:3; expected ELSE or END"
+            ),
+            err(1, 21, "END without matching block"),
+        ]
+    );
+}
+
+#[test]
+fn error_after_stray_semicolon() {
+    assert_eq!(
+        errors("; 5 . foo"),
+        [
+            err(1, 1, "; without matching :"),
+            err(1, 7, "Invalid: foo undefined"),
+        ]
+    );
+    assert_eq!(
+        errors("1 if 2 . end ; foo"),
+        [
+            err(1, 14, "; without matching :"),
+            err(1, 16, "Invalid: foo undefined"),
+        ]
+    );
+}
+
+#[test]
+fn failed_word_skips_its_semicolon() {
+    assert_eq!(
+        errors(": 5 1 + ; 2 . foo"),
+        [
+            err(1, 3, "New word error: Invalid name"),
+            err(1, 15, "Invalid: foo undefined"),
+        ]
+    );
+}
+
+fn write(name: &str, source: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, source).unwrap();
+    path
+}
+
+#[test]
+fn max_errors() {
+    let path = write("max_errors.rorth", "a\nb\nc\nd\ne\n");
+    let check = |extra: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_rorth"))
+            .arg("check")
+            .args(extra)
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1));
+        String::from_utf8(out.stderr).unwrap()
+    };
+    let all = check(&[]);
+    assert_eq!(all.matches("error:").count(), 5);
+    assert!(!all.contains("more errors"), "{}", all);
+    let some = check(&["--max-errors", "2"]);
+    assert_eq!(some.matches("error:").count(), 2, "{}", some);
+    assert!(some.contains("Invalid: a undefined"), "{}", some);
+    assert!(some.contains("Invalid: b undefined"), "{}", some);
+    assert!(some.contains("... and 3 more errors"), "{}", some);
+}
@@ -0,0 +1,38 @@
+use rorth::{lexer::lexer, parser::parser, qbe_backend::qbe_backend};
+
+fn emit(source: &str) -> String {
+    let mut l = lexer::new("test.rorth".to_string(), source.to_string());
+    let program = parser::new(l.lex().unwrap()).parse().unwrap();
+    qbe_backend::new().compile(&program).unwrap()
+}
+
+// The data definitions of the program's strings
+fn data(ssa: &str) -> Vec<&str> {
+    ssa.lines()
+        .filter(|l| l.starts_with("data $str_"))
+        .collect()
+}
+
+#[test]
+fn escaped_bytes_in_string_data() {
+    let ssa = emit(r#""a\n\tb\\c\"d\x41\x7f" . "ok" . "ok" ."#);
+    assert_eq!(
+        data(&ssa),
+        [
+            r#"data $str_0 = { b "a", b 10, b 9, b "b", b 92, b "c", b 34, b "dA", b 127, b 0 }"#,
+            r#"data $str_1 = { b "ok", b 0 }"#,
+        ]
+    );
+}
+
+#[test]
+fn empty_string_and_nul() {
+    let ssa = emit(r#""" . "\0x" ."#);
+    assert_eq!(
+        data(&ssa),
+        [
+            r#"data $str_0 = { b 0 }"#,
+            r#"data $str_1 = { b 0, b "x", b 0 }"#,
+        ]
+    );
+}
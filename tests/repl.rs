@@ -0,0 +1,110 @@
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+// Runs the repl on input in dir and returns what it printed, stdout then stderr
+fn repl_in(dir: &PathBuf, input: &str) -> (String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rorth"))
+        .arg("repl")
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    (
+        String::from_utf8(out.stdout).unwrap(),
+        String::from_utf8(out.stderr).unwrap(),
+    )
+}
+
+fn repl(input: &str) -> (String, String) {
+    repl_in(&PathBuf::from(env!("CARGO_TARGET_TMPDIR")), input)
+}
+
+// The stack printed after each line of input
+fn stacks(stdout: &str) -> Vec<String> {
+    stdout
+        .lines()
+        .filter_map(|l| l.rsplit_once('<').map(|(_, s)| format!("<{}", s)))
+        .collect()
+}
+
+#[test]
+fn stack_persists() {
+    let (out, _) = repl("1 2\n+\n\"s\" 4.5\n");
+    assert_eq!(stacks(&out), ["<2> 1 2", "<1> 3", "<3> 3 \"s\" 4.500000"]);
+}
+
+#[test]
+fn stack_types_persist() {
+    let (out, err) = repl("\"s\"\n1 +\n");
+    assert_eq!(stacks(&out), ["<1> \"s\"", "<1> \"s\""]);
+    assert!(
+        err.contains("`+` expects int int or float float, got str int"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn multi_line_definitions() {
+    let (out, _) = repl(": sq ( a -- b )\ndup\n* ;\n7 sq\n");
+    assert!(out.contains(".. .. "), "{}", out);
+    assert_eq!(stacks(&out), ["<0>", "<1> 49"]);
+}
+
+#[test]
+fn failed_lines_are_undone() {
+    let (out, err) = repl("5 x :=\n: f ( -- a ) 1 ; 3 x := 1 0 /\nf\n.words\nx\n");
+    assert!(err.contains("Division by zero"), "{}", err);
+    assert!(err.contains("f undefined"), "{}", err);
+    assert!(!out.contains("f ( -- a )"), "{}", out);
+    assert_eq!(stacks(&out), ["<0>", "<0>", "<0>", "<0>", "<1> 5"]);
+}
+
+#[test]
+fn failed_redefinitions_are_undone() {
+    let (out, _) = repl(": g ( -- a ) 1 ;\n: g ( -- a ) 2 ; 1 0 /\ng\n");
+    assert_eq!(stacks(&out), ["<0>", "<0>", "<1> 1"]);
+}
+
+#[test]
+fn words_and_clear() {
+    let (out, _) = repl(": a 1 ;\n: b ( -- x ) 2 ;\n: a 3 ;\n.words\n1 2 3\n.clear\n");
+    assert!(out.contains("b ( -- x )\na\n"), "{}", out);
+    assert_eq!(
+        stacks(&out),
+        ["<0>", "<0>", "<0>", "<0>", "<3> 1 2 3", "<0>"]
+    );
+}
+
+#[test]
+fn load() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("repl_load");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("lib.rorth"), ": twice ( a -- b ) 2 * ; 10 n :=").unwrap();
+    fs::write(dir.join("bad.rorth"), "1 2 foo").unwrap();
+    let (out, err) = repl_in(
+        &dir,
+        ".load lib.rorth\nn twice\n.load bad.rorth\n.load missing.rorth\n",
+    );
+    assert_eq!(stacks(&out), ["<0>", "<1> 20", "<1> 20", "<1> 20"]);
+    assert!(
+        err.contains("bad.rorth:1:5: error: Invalid: foo undefined"),
+        "{}",
+        err
+    );
+    assert!(err.contains("Error reading missing.rorth"), "{}", err);
+}
@@ -0,0 +1,109 @@
+use std::thread;
+
+use rorth::{lexer::lexer, parser::parser, typechecker::typechecker};
+
+// Message of the first type error in source, if there is one
+fn check(source: &str) -> Result<(), String> {
+    let mut l = lexer::new("test.rorth".to_string(), source.to_string());
+    let tokens = l.lex().map_err(|e| e[0].message.clone())?;
+    let program = parser::new(tokens)
+        .parse()
+        .map_err(|e| e[0].message.clone())?;
+    typechecker::new().check(&program).map_err(|e| e.message)
+}
+
+#[test]
+fn str_plus_int() {
+    assert_eq!(
+        check("\"a\" 1 +"),
+        Err("`+` expects int int or float float, got str int".to_string())
+    );
+    assert_eq!(check("1 2 +"), Ok(()));
+    assert_eq!(check("1.5 2.5 +"), Ok(()));
+}
+
+#[test]
+fn flags() {
+    assert_eq!(check("1 2 < if 3 . end"), Ok(()));
+    assert_eq!(
+        check("0 n := begin n 3 < nip nip while n 1 + n := repeat"),
+        Ok(())
+    );
+    assert_eq!(check("1 if 2 . end"), Ok(()));
+    assert_eq!(
+        check("\"s\" if 2 . end"),
+        Err("`if` expects bool or int, got str".to_string())
+    );
+    assert_eq!(
+        check("begin 1.5 while repeat"),
+        Err("`while` expects bool or int, got float".to_string())
+    );
+}
+
+#[test]
+fn variable_changes_type_in_a_loop() {
+    assert_eq!(
+        check("1 x := begin 1 while x 1 + drop \"s\" x := repeat"),
+        Err("`+` expects int int or float float, got str int".to_string())
+    );
+    assert_eq!(check("1 x := begin 1 while x 1 + x := repeat"), Ok(()));
+}
+
+#[test]
+fn branches() {
+    // The same types on both sides are kept
+    assert_eq!(
+        check("1 if \"a\" else \"b\" end 1 +"),
+        Err("`+` expects int int or float float, got str int".to_string())
+    );
+    // Different types merge to any, and different depths leave the stack unknown
+    assert_eq!(check("1 if 1 else \"s\" end 2 +"), Ok(()));
+    assert_eq!(check("1 if 1 2 else 3 end drop drop drop"), Ok(()));
+    assert_eq!(check("1 if 1 x := else \"s\" x := end x 2 +"), Ok(()));
+}
+
+#[test]
+fn recursive_word() {
+    let fact = ": fact ( n -- n ) dup 1 > nip nip if dup 1 - fact * end ; ";
+    assert_eq!(check(&format!("{}5 fact .", fact)), Ok(()));
+    assert_eq!(
+        check(&format!("{}5 fact \"s\" +", fact)),
+        Err("`+` expects int int or float float, got int str".to_string())
+    );
+    assert_eq!(
+        check(&format!("{}\"s\" fact", fact)),
+        Err("`>` expects int int or float float, got str int".to_string())
+    );
+}
+
+// A chain of words where each calls the one defined before it
+fn chain(n: usize, signature: &str) -> String {
+    let mut s = format!(": w0 {} 1 + ;\n", signature);
+    for i in 1..=n {
+        s.push_str(&format!(": w{} {} w{} ;\n", i, signature, i - 1));
+    }
+    s.push_str(&format!("1 w{} .", n));
+    s
+}
+
+// Runs f with the 8 MiB stack rorth's main thread gets; test threads get less
+fn with_main_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(f)
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn deep_call_chains() {
+    assert_eq!(
+        with_main_stack(|| check(&chain(3000, "( a -- a )"))),
+        Ok(())
+    );
+    assert_eq!(
+        with_main_stack(|| check(&chain(3000, ""))),
+        Err("Calls nested too deeply to check; give w2489 a signature".to_string())
+    );
+}